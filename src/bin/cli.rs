@@ -1,5 +1,21 @@
 use clap::{Args, Parser, Subcommand};
+use flytile::cache;
+use flytile::catalog;
+use flytile::earthdata;
+use flytile::elevation;
+use flytile::import;
+use flytile::regional;
+use flytile::seed;
+use flytile::sentinel;
+use flytile::slope;
+use flytile::srtm;
 use flytile::tile;
+use flytile::token;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
@@ -12,6 +28,16 @@ struct Cli {
 enum Commands {
     ToTile(ToTileArgs),
     ToGeo(ToGeoArgs),
+    /// Log in to Earthdata and check SRTM data can be downloaded
+    CheckCredentials,
+    /// Copy .hgt, .hgt.zip or GeoTIFF elevation files into the SRTM cache
+    Import(ImportArgs),
+    /// Generate the tiles of an area ahead of time
+    Seed(SeedArgs),
+    /// Add high resolution GeoTIFF elevation to the regional DEM registry
+    RegisterDem(RegisterDemArgs),
+    /// List the imagery scenes over an area as GeoJSON
+    Scenes(ScenesArgs),
 }
 
 #[derive(Args, Debug)]
@@ -21,6 +47,69 @@ struct ToTileArgs {
     latitude: f64,
 }
 
+#[derive(Args, Debug)]
+struct ImportArgs {
+    /// file, directory or .tar/.tar.gz archive to import
+    input: PathBuf,
+    /// SRTM cache directory, defaults to srtm in $FLYTILE_CACHE_DIR
+    #[arg(long)]
+    cache: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SeedArgs {
+    /// area as min_longitude,min_latitude,max_longitude,max_latitude
+    #[arg(long, required_unless_present = "polygon", allow_hyphen_values = true)]
+    bbox: Option<String>,
+    /// GeoJSON file with a polygon outlining the area
+    #[arg(long, conflicts_with = "bbox")]
+    polygon: Option<PathBuf>,
+    #[arg(long, default_value_t = tile::MIN_ZOOM)]
+    min_zoom: u8,
+    #[arg(long, default_value_t = tile::MAX_ZOOM)]
+    max_zoom: u8,
+    /// comma separated layers out of elevation, slope and imagery
+    #[arg(long, value_delimiter = ',', default_value = "elevation,slope,imagery")]
+    layers: Vec<seed::Layer>,
+    /// number of tiles to generate at once
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+    /// progress file used to resume, defaults to seed.json in $FLYTILE_CACHE_DIR
+    #[arg(long)]
+    state: Option<PathBuf>,
+    /// only print an estimate of the work
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+struct RegisterDemArgs {
+    /// GeoTIFF elevation files
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// registry file, defaults to $FLYTILE_REGIONAL_DEMS or regional.json in $FLYTILE_CACHE_DIR
+    #[arg(long)]
+    registry: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ScenesArgs {
+    /// area as min_longitude,min_latitude,max_longitude,max_latitude
+    #[arg(long, allow_hyphen_values = true)]
+    bbox: String,
+    /// first day searched like 2025-01-01, defaults to 30 days before to
+    #[arg(long, requires = "to")]
+    from: Option<String>,
+    /// last day searched, defaults to today
+    #[arg(long, requires = "from")]
+    to: Option<String>,
+    /// maximum cloud coverage in percent
+    #[arg(long)]
+    cloud: Option<f64>,
+    #[arg(long, default_value = catalog::DEFAULT_COLLECTION)]
+    collection: String,
+}
+
 #[derive(Args, Debug)]
 struct ToGeoArgs {
     zoom: u8,
@@ -29,6 +118,7 @@ struct ToGeoArgs {
 }
 
 fn main() {
+    env_logger::init();
     let cli = Cli::parse();
     match &cli.command {
         Commands::ToTile(args) => {
@@ -65,5 +155,192 @@ fn main() {
             println!("tile north west corner:     {:?}", meters_nw);
             println!("tile south east corner:     {:?}", meters_se);
         }
+        Commands::CheckCredentials => {
+            let result = earthdata::Credentials::from_env().and_then(|credentials| {
+                println!("using earthdata credentials {:?}", credentials);
+                srtm::check_credentials(&credentials)
+            });
+            match result {
+                Ok(()) => println!("earthdata credentials ok"),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Import(args) => {
+            let cache = args.cache.clone().unwrap_or_else(|| {
+                PathBuf::from(env::var("FLYTILE_CACHE_DIR").unwrap_or("/tmp".to_string()))
+                    .join("srtm")
+            });
+            // the same limits as the server's srtm cache, nothing is evicted here
+            let result = cache::Cache::from_existing_directory(
+                cache.clone(),
+                10_000_000_000,
+                100_000_000,
+                86400 * 365,
+            )
+            .and_then(|mut srtm_cache| import::import(&args.input, &mut srtm_cache));
+            let report = match result {
+                Ok(report) => report,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    process::exit(1);
+                }
+            };
+            println!(
+                "imported {} cells into {:?}, {} already present",
+                report.imported.len(),
+                cache,
+                report.skipped.len()
+            );
+            for (path, reason) in &report.rejected {
+                eprintln!("rejected {:?}: {}", path, reason);
+            }
+            if !report.rejected.is_empty() {
+                process::exit(1);
+            }
+        }
+        Commands::RegisterDem(args) => {
+            let mut registry = match &args.registry {
+                Some(path) => regional::Registry::load(path.clone()),
+                None => regional::Registry::from_env(&PathBuf::from(
+                    env::var("FLYTILE_CACHE_DIR").unwrap_or("/tmp".to_string()),
+                )),
+            };
+            for file in &args.files {
+                match regional::RegionalDem::from_file(file) {
+                    Ok(dem) => {
+                        println!(
+                            "registered {:?} at {:.2} m up to zoom {}, {:?}",
+                            dem.path,
+                            dem.resolution,
+                            dem.max_zoom(),
+                            dem.bounds
+                        );
+                        registry.register(dem);
+                    }
+                    Err(error) => {
+                        eprintln!("could not register {:?}: {}", file, error);
+                        process::exit(1);
+                    }
+                }
+            }
+            if let Err(error) = registry.save() {
+                eprintln!("could not save registry: {}", error);
+                process::exit(1);
+            }
+        }
+        Commands::Seed(args) => {
+            if let Err(error) = run_seed(args) {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+        }
+        Commands::Scenes(args) => {
+            if let Err(error) = run_scenes(args) {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn run_scenes(args: &ScenesArgs) -> anyhow::Result<()> {
+    let bounds = seed::Region::parse_bbox(&args.bbox)?.bounds();
+    let options = sentinel::ImageryOptions::parse(
+        args.cloud,
+        args.from.as_deref(),
+        args.to.as_deref(),
+        None,
+        None,
+    )?;
+    let catalog = catalog::Catalog::new(catalog::URL, token::Generator::new(sentinel::TOKEN_URL));
+    let runtime = tokio::runtime::Runtime::new()?;
+    let acquisitions = runtime.block_on(catalog.search(&args.collection, &bounds, &options))?;
+    for acquisition in &acquisitions {
+        eprintln!(
+            "{} {:>5} {}",
+            acquisition.date(),
+            acquisition
+                .cloud_cover
+                .map(|cloud| format!("{:.0}%", cloud))
+                .unwrap_or("-".into()),
+            acquisition.id
+        );
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&catalog::to_geojson(&acquisitions))?
+    );
+    Ok(())
+}
+
+fn run_seed(args: &SeedArgs) -> anyhow::Result<()> {
+    let region = match (&args.bbox, &args.polygon) {
+        (Some(bbox), _) => seed::Region::parse_bbox(bbox)?,
+        (None, Some(path)) => seed::Region::from_geojson(&fs::read_to_string(path)?)?,
+        (None, None) => unreachable!("clap requires bbox or polygon"),
+    };
+    let max_zoom = args
+        .layers
+        .iter()
+        .map(seed::Layer::max_zoom)
+        .max()
+        .unwrap_or(tile::MAX_ZOOM);
+    if args.min_zoom < tile::MIN_ZOOM || args.max_zoom > max_zoom {
+        anyhow::bail!(
+            "tiles are only served for zoom {} to {}",
+            tile::MIN_ZOOM,
+            max_zoom
+        );
+    }
+    let job = seed::Job {
+        region,
+        min_zoom: args.min_zoom,
+        max_zoom: args.max_zoom,
+        layers: args.layers.clone(),
+    };
+    let cache = PathBuf::from(env::var("FLYTILE_CACHE_DIR").unwrap_or("/tmp".to_string()));
+    let source = elevation::source_from_env()?;
+    let tasks = job.tasks(source.as_ref());
+    let state_path = args.state.clone().unwrap_or(cache.join("seed.json"));
+    let mut state = seed::SeedState::load(state_path, &job);
+    if args.dry_run {
+        let remaining: Vec<seed::Task> = tasks
+            .into_iter()
+            .filter(|task| !state.is_done(task))
+            .collect();
+        let estimate = seed::estimate(&remaining);
+        println!("elevation cells:  {}", estimate.cells);
+        println!("slope tiles:      {}", estimate.slope_tiles);
+        println!("imagery tiles:    {}", estimate.imagery_tiles);
+        println!("disk usage:       {:.1} MB", estimate.bytes as f64 / 1e6);
+        println!("processing units: {:.2}", estimate.processing_units);
+        return Ok(());
+    }
+
+    let elevation = Arc::new(elevation::Elevation::from_env(&cache)?);
+    let mut seeder = seed::Seeder::new(args.concurrency).with_elevation(elevation.clone());
+    if job.layers.contains(&seed::Layer::Slope) {
+        seeder = seeder.with_slope(slope::Pipeline::new(cache.join("slope"), elevation));
+    }
+    if job.layers.contains(&seed::Layer::Imagery) {
+        seeder = seeder.with_sentinel(sentinel::Sentinel::new(cache.join("sentinel")));
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    let progress = runtime.block_on(seeder.run(tasks, &mut state, |progress| {
+        eprint!(
+            "\rseeded {} of {} ({} from earlier runs, {} failed)",
+            progress.done + progress.skipped,
+            progress.total,
+            progress.skipped,
+            progress.failed
+        );
+    }));
+    eprintln!();
+    if progress.failed > 0 {
+        anyhow::bail!("{} tasks failed, run again to retry them", progress.failed);
     }
+    Ok(())
 }
@@ -1,9 +1,20 @@
 #[macro_use]
 extern crate rocket;
+use flytile::cache;
+use flytile::catalog;
+use flytile::change;
+use flytile::elevation;
+use flytile::evalscript;
+use flytile::regional;
+use flytile::retry;
+use flytile::sar;
+use flytile::seed;
 use flytile::sentinel;
 use flytile::slope;
-use flytile::srtm;
+use flytile::snow;
 use flytile::tile;
+use flytile::token;
+use flytile::usage;
 use flytile::viewer;
 use maud::Markup;
 use rocket::fairing::Fairing;
@@ -11,27 +22,57 @@ use rocket::fairing::Info;
 use rocket::fairing::Kind;
 use rocket::fs::FileServer;
 use rocket::fs::NamedFile;
+use rocket::http::ContentType;
+use rocket::http::Header;
+use rocket::http::Status;
+use rocket::response::content::RawJson;
+use rocket::response::status::BadRequest;
 use rocket::State;
 use rocket::{Request, Response};
 use std::borrow::Cow;
 use std::env;
 use std::path;
+use std::sync::Arc;
 
 #[launch]
 fn rocket() -> _ {
     let cache = env::var("FLYTILE_CACHE_DIR").unwrap_or("/tmp".into());
+    let elevation = Arc::new(elevation::Elevation::from_env(path::Path::new(&cache)).unwrap());
+    let usage = Arc::new(usage::Usage::from_env(path::Path::new(&cache)));
+    let limit = Arc::new(retry::Limit::from_env());
     rocket::build()
         .attach(AnyOrigin)
-        .manage(srtm::SRTM::new(path::Path::new(&cache).join("srtm")))
-        .manage(slope::Pipeline::new(path::Path::new(&cache).join("slope")))
-        .manage(sentinel::Sentinel::new(
-            path::Path::new(&cache).join("sentinel"),
+        .manage(
+            slope::Pipeline::new(path::Path::new(&cache).join("slope"), elevation)
+                .with_regional(regional::Registry::from_env(path::Path::new(&cache))),
+        )
+        .manage(
+            sentinel::Sentinel::new(path::Path::new(&cache).join("sentinel"))
+                .with_scripts(evalscript::Library::from_env().unwrap())
+                .with_usage(usage.clone())
+                .with_limit(limit.clone()),
+        )
+        .manage(snow::SteepSnow::new(
+            path::Path::new(&cache).join("steep-snow"),
+        ))
+        .manage(
+            sar::Sar::new(path::Path::new(&cache).join("sentinel-1"))
+                .with_usage(usage)
+                .with_limit(limit),
+        )
+        .manage(change::Change::new(path::Path::new(&cache).join("change")))
+        .manage(catalog::Catalog::new(
+            catalog::URL,
+            token::Generator::new(sentinel::TOKEN_URL),
         ))
         .mount("/", routes![index])
         .mount("/css", FileServer::from("css"))
         .mount("/grid", routes![grid])
         .mount("/slope", routes![slope_tiles])
-        .mount("/imagery/latest", routes![image_tiles])
+        .mount("/imagery", routes![image_tiles, scenes, usage_status])
+        .mount("/steep-snow", routes![steep_snow_tiles])
+        .mount("/sar", routes![sar_tiles])
+        .mount("/change", routes![change_tiles])
 }
 
 struct AnyOrigin;
@@ -50,8 +91,20 @@ impl Fairing for AnyOrigin {
     }
 }
 
-#[get("/")]
-fn index() -> Markup {
+/// Viewer page, around a tile if given by `zoom`, `x` and `y`, showing
+/// imagery between `from` and `to` dates
+#[get("/?<zoom>&<x>&<y>&<from>&<to>")]
+fn index(
+    zoom: Option<u8>,
+    x: Option<u32>,
+    y: Option<u32>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Markup {
+    let dates = viewer::Dates::new(from, to);
+    if let (Some(zoom), Some(x), Some(y)) = (zoom, x, y) {
+        return viewer::viewer(zoom, x, y, &dates);
+    }
     let point = tile::GeoPoint {
         longitude: -119.59018,
         latitude: 49.49230,
@@ -59,11 +112,11 @@ fn index() -> Markup {
     let zoom = 12;
     let square = tile::geodetic_to_square(&point);
     let point = tile::square_to_tile(zoom, &square);
-    viewer::viewer(zoom, point.x as u32, point.y as u32)
+    viewer::viewer(zoom, point.x as u32, point.y as u32, &dates)
 }
 
-#[get("/<zoom>/<x>/<y_with_extension>")]
-fn grid(zoom: u8, x: u32, y_with_extension: &str) -> Markup {
+#[get("/<zoom>/<x>/<y_with_extension>?<from>&<to>")]
+fn grid(zoom: u8, x: u32, y_with_extension: &str, from: Option<&str>, to: Option<&str>) -> Markup {
     let y = y_with_extension
         .strip_suffix(".png")
         .unwrap()
@@ -76,52 +129,459 @@ fn grid(zoom: u8, x: u32, y_with_extension: &str) -> Markup {
     // let zoom = 12;
     // let square = tile::geodetic_to_square(&point);
     // let point = tile::square_to_tile(zoom, &square);
-    viewer::image_grid(zoom, x, y, 9, 5)
+    viewer::image_grid(zoom, x, y, 9, 5, &viewer::Dates::new(from, to))
 }
 
+#[derive(Responder)]
+enum SlopeResponse {
+    Tile(NamedFile, Header<'static>),
+    Metadata(RawJson<String>),
+}
+
+/// Slope tiles as `<y>.png` and a description of how they were made as `<y>.json`
 #[get("/<zoom>/<x>/<y_with_extension>")]
 async fn slope_tiles(
-    elev: &State<srtm::SRTM>,
     pipe: &State<slope::Pipeline>,
     zoom: u8,
     x: u32,
     y_with_extension: &str,
-) -> Option<NamedFile> {
-    if zoom < 10 || zoom > 14 {
+) -> Option<SlopeResponse> {
+    let (y, extension) = y_with_extension.split_once('.')?;
+    let y = y.parse::<u32>().ok()?;
+    if !pipe.serves(zoom, x, y) {
         // todo support coarser zoom levels using coarser source data
         return None;
     }
-    let y = y_with_extension
-        .strip_suffix(".png")
-        .unwrap()
-        .parse::<u32>()
-        .unwrap();
-    log::info!("generating srtm slope tile {} {} {}", zoom, x, y);
-    let bounds = tile::tile_bounds(zoom, x, y);
-    log::debug!("tile bounds {:?}", bounds);
-    let elevations = elev.get_all(bounds).await.unwrap();
-    log::debug!("elevations {:?}", elevations);
-    let shade = pipe.get(elevations, zoom, x, y).await.unwrap();
-    NamedFile::open(&shade).await.ok()
+    log::info!("generating slope tile {} {} {}", zoom, x, y);
+    let shade = match pipe.get(zoom, x, y).await {
+        Ok(shade) => shade,
+        Err(error) => {
+            log::error!("could not make slope tile {} {} {}: {}", zoom, x, y, error);
+            return None;
+        }
+    };
+    let metadata = slope::read_metadata(&shade);
+    match extension {
+        "png" => {
+            let interpolated = metadata
+                .map(|metadata| metadata.interpolated.to_string())
+                .unwrap_or("unknown".into());
+            Some(SlopeResponse::Tile(
+                NamedFile::open(&shade).await.ok()?,
+                Header::new("X-Flytile-Interpolated", interpolated),
+            ))
+        }
+        "json" => Some(SlopeResponse::Metadata(RawJson(
+            serde_json::to_string(&metadata?).ok()?,
+        ))),
+        _ => None,
+    }
 }
 
-#[get("/<zoom>/<x>/<y_with_extension>")]
+/// Imagery search limited by `cloud` percent and either `from` and `to` or
+/// `before` dates like `2025-02-01`, preferring the `recent` or
+/// `least-cloudy` scene by `order`, with `stamp` the date drawn on and
+/// `upsampling` and `downsampling` as `nearest`, `bilinear` or `bicubic`, from
+/// the `sentinel` (default), `landsat` or `fallback` to Landsat `source`
+#[derive(FromForm)]
+struct ImageryQuery<'r> {
+    cloud: Option<f64>,
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    before: Option<&'r str>,
+    order: Option<&'r str>,
+    stamp: Option<bool>,
+    upsampling: Option<&'r str>,
+    downsampling: Option<&'r str>,
+    source: Option<&'r str>,
+}
+
+#[derive(Responder)]
+enum ImageryResponse {
+    Tile(NamedFile, Header<'static>, Header<'static>),
+    Metadata(RawJson<String>),
+    Placeholder(Vec<u8>, ContentType),
+    /// upstream is throttling or briefly failing, the tile may be asked again
+    Unavailable(Status),
+}
+
+/// Imagery rendered by a named evalscript as `<y>.png`, `latest` is true
+/// colour, and the scenes it was made from as `<y>.json`
+#[get("/<script>/<zoom>/<x>/<y_with_extension>?<query..>")]
 async fn image_tiles(
     provider: &State<sentinel::Sentinel>,
+    script: &str,
     zoom: u8,
     x: u32,
     y_with_extension: &str,
-) -> Option<NamedFile> {
-    if zoom < 10 || zoom > 14 {
+    query: ImageryQuery<'_>,
+) -> Result<Option<ImageryResponse>, BadRequest<String>> {
+    if !(tile::MIN_ZOOM..=sentinel::MAX_ZOOM).contains(&zoom) {
         // todo support coarser zoom levels using coarser source data
-        return None;
+        return Ok(None);
+    }
+    if provider.scripts().get(script).is_none() {
+        return Ok(None);
+    }
+    let Some((y, extension)) = y_with_extension.split_once('.') else {
+        return Ok(None);
+    };
+    let Ok(y) = y.parse::<u32>() else {
+        return Ok(None);
+    };
+    let options = sentinel::ImageryOptions::parse(
+        query.cloud,
+        query.from,
+        query.to,
+        query.before,
+        query.order,
+    )
+    .and_then(|options| options.with_resampling(query.upsampling, query.downsampling))
+    .and_then(|options| options.with_source(query.source))
+    .map_err(|error| BadRequest(error.to_string()))?
+    .with_stamp(query.stamp.unwrap_or(false));
+    log::info!(
+        "generating sentinel {} imagery tile {} {} {} {:?}",
+        script,
+        zoom,
+        x,
+        y,
+        options
+    );
+    let path = match provider.get(zoom, x, y, script, &options).await {
+        Ok(path) => path,
+        Err(error) if error.is::<usage::BudgetExceeded>() => {
+            log::warn!("not fetching imagery tile {} {} {}: {}", zoom, x, y, error);
+            return Ok(Some(ImageryResponse::Placeholder(
+                usage::placeholder(sentinel::TILE_SIZE),
+                ContentType::PNG,
+            )));
+        }
+        Err(error)
+            if error
+                .downcast_ref::<cache::GeneratorError>()
+                .is_some_and(|error| error.kind() == &cache::ErrorKind::Retryable) =>
+        {
+            log::warn!("imagery tile {} {} {} unavailable: {}", zoom, x, y, error);
+            return Ok(Some(ImageryResponse::Unavailable(
+                Status::ServiceUnavailable,
+            )));
+        }
+        Err(error) => {
+            log::error!(
+                "could not make imagery tile {} {} {}: {}",
+                zoom,
+                x,
+                y,
+                error
+            );
+            return Ok(None);
+        }
+    };
+    let metadata = sentinel::read_metadata(&path);
+    match extension {
+        "png" => {
+            let date = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.date.clone())
+                .unwrap_or("unknown".into());
+            let cloud = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.cloud_coverage())
+                .map(|cloud| cloud.to_string())
+                .unwrap_or("unknown".into());
+            Ok(NamedFile::open(&path).await.ok().map(|file| {
+                ImageryResponse::Tile(
+                    file,
+                    Header::new("X-Flytile-Scene-Date", date),
+                    Header::new("X-Flytile-Cloud-Coverage", cloud),
+                )
+            }))
+        }
+        "json" => Ok(metadata
+            .and_then(|metadata| serde_json::to_string(&metadata).ok())
+            .map(|json| ImageryResponse::Metadata(RawJson(json)))),
+        _ => Ok(None),
+    }
+}
+
+/// Processing units spent this month and the budget left
+#[get("/usage")]
+fn usage_status(provider: &State<sentinel::Sentinel>) -> Option<RawJson<String>> {
+    serde_json::to_string(&provider.usage().status())
+        .ok()
+        .map(RawJson)
+}
+
+/// Scenes over a `bbox` of min_longitude,min_latitude,max_longitude,max_latitude
+/// searched like imagery tiles, from the `collection` named as in evalscripts
+#[derive(FromForm)]
+struct ScenesQuery<'r> {
+    bbox: &'r str,
+    collection: Option<&'r str>,
+    cloud: Option<f64>,
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    before: Option<&'r str>,
+}
+
+/// Acquisitions available over an area as GeoJSON footprints
+#[get("/scenes?<query..>")]
+async fn scenes(
+    catalog: &State<catalog::Catalog>,
+    query: ScenesQuery<'_>,
+) -> Result<Option<RawJson<String>>, BadRequest<String>> {
+    let bounds = seed::Region::parse_bbox(query.bbox)
+        .map_err(|error| BadRequest(error.to_string()))?
+        .bounds();
+    let options =
+        sentinel::ImageryOptions::parse(query.cloud, query.from, query.to, query.before, None)
+            .map_err(|error| BadRequest(error.to_string()))?;
+    let collection = query.collection.unwrap_or(catalog::DEFAULT_COLLECTION);
+    match catalog.search(collection, &bounds, &options).await {
+        Ok(acquisitions) => Ok(Some(RawJson(
+            catalog::to_geojson(&acquisitions).to_string(),
+        ))),
+        Err(error) => {
+            log::error!("could not search for scenes: {}", error);
+            Ok(None)
+        }
+    }
+}
+
+/// Tiles made from imagery, a placeholder once the imagery budget is spent
+#[derive(Responder)]
+enum ProductResponse {
+    Tile(NamedFile),
+    Placeholder(Vec<u8>, ContentType),
+}
+
+impl ProductResponse {
+    fn placeholder() -> Self {
+        ProductResponse::Placeholder(usage::placeholder(sentinel::TILE_SIZE), ContentType::PNG)
+    }
+}
+
+/// Slope shading kept only where the snow layer finds snow
+#[get("/<zoom>/<x>/<y_with_extension>?<query..>")]
+async fn steep_snow_tiles(
+    pipe: &State<slope::Pipeline>,
+    provider: &State<sentinel::Sentinel>,
+    steep_snow: &State<snow::SteepSnow>,
+    zoom: u8,
+    x: u32,
+    y_with_extension: &str,
+    query: ImageryQuery<'_>,
+) -> Result<Option<ProductResponse>, BadRequest<String>> {
+    if !(tile::MIN_ZOOM..=tile::MAX_ZOOM).contains(&zoom) {
+        return Ok(None);
+    }
+    let Some(y) = y_with_extension.strip_suffix(".png") else {
+        return Ok(None);
+    };
+    let Ok(y) = y.parse::<u32>() else {
+        return Ok(None);
+    };
+    let options = sentinel::ImageryOptions::parse(
+        query.cloud,
+        query.from,
+        query.to,
+        query.before,
+        query.order,
+    )
+    .map_err(|error| BadRequest(error.to_string()))?;
+    log::info!(
+        "generating steep snow tile {} {} {} {:?}",
+        zoom,
+        x,
+        y,
+        options
+    );
+    match steep_snow.get(pipe, provider, zoom, x, y, &options).await {
+        Ok(path) => Ok(NamedFile::open(&path).await.ok().map(ProductResponse::Tile)),
+        Err(error) if error.is::<usage::BudgetExceeded>() => {
+            log::warn!(
+                "not fetching steep snow tile {} {} {}: {}",
+                zoom,
+                x,
+                y,
+                error
+            );
+            Ok(Some(ProductResponse::placeholder()))
+        }
+        Err(error) => {
+            log::error!(
+                "could not make steep snow tile {} {} {}: {}",
+                zoom,
+                x,
+                y,
+                error
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Radar search by `from` and `to` or `before` dates, with a `reference` date
+/// to show the change since the scene before it
+#[derive(FromForm)]
+struct SarQuery<'r> {
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    before: Option<&'r str>,
+    reference: Option<&'r str>,
+}
+
+/// Sentinel-1 backscatter, which sees through cloud
+#[get("/<script>/<zoom>/<x>/<y_with_extension>?<query..>")]
+async fn sar_tiles(
+    provider: &State<sar::Sar>,
+    script: &str,
+    zoom: u8,
+    x: u32,
+    y_with_extension: &str,
+    query: SarQuery<'_>,
+) -> Result<Option<ProductResponse>, BadRequest<String>> {
+    if !(tile::MIN_ZOOM..=tile::MAX_ZOOM).contains(&zoom) {
+        return Ok(None);
+    }
+    if provider.scripts().get(script).is_none() {
+        return Ok(None);
+    }
+    let Some(y) = y_with_extension.strip_suffix(".png") else {
+        return Ok(None);
+    };
+    let Ok(y) = y.parse::<u32>() else {
+        return Ok(None);
+    };
+    let options = sentinel::ImageryOptions::parse(None, query.from, query.to, query.before, None)
+        .map_err(|error| BadRequest(error.to_string()))?;
+    let result = match query.reference {
+        Some(reference) => {
+            let reference =
+                sentinel::ImageryOptions::parse(None, None, None, Some(reference), None)
+                    .map_err(|error| BadRequest(error.to_string()))?;
+            log::info!(
+                "generating sar {} ratio tile {} {} {} {:?} {:?}",
+                script,
+                zoom,
+                x,
+                y,
+                reference,
+                options
+            );
+            provider
+                .get_ratio(zoom, x, y, script, &reference, &options)
+                .await
+        }
+        None => {
+            log::info!(
+                "generating sar {} tile {} {} {} {:?}",
+                script,
+                zoom,
+                x,
+                y,
+                options
+            );
+            provider.get(zoom, x, y, script, &options).await
+        }
+    };
+    match result {
+        Ok(path) => Ok(NamedFile::open(&path).await.ok().map(ProductResponse::Tile)),
+        Err(error) if error.is::<usage::BudgetExceeded>() => {
+            log::warn!("not fetching sar tile {} {} {}: {}", zoom, x, y, error);
+            Ok(Some(ProductResponse::placeholder()))
+        }
+        Err(error) => {
+            log::error!("could not make sar tile {} {} {}: {}", zoom, x, y, error);
+            Ok(None)
+        }
+    }
+}
+
+/// Two imagery searches as for imagery, the earlier given by `reference_from`
+/// and `reference_to` or `reference_before`, and the output `band` of a
+/// script to compare
+#[derive(FromForm)]
+struct ChangeQuery<'r> {
+    cloud: Option<f64>,
+    order: Option<&'r str>,
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    before: Option<&'r str>,
+    reference_from: Option<&'r str>,
+    reference_to: Option<&'r str>,
+    reference_before: Option<&'r str>,
+    band: Option<usize>,
+}
+
+/// Change between two dates in NDSI (`ndsi`) or a band of a script's imagery
+#[get("/<product>/<zoom>/<x>/<y_with_extension>?<query..>")]
+async fn change_tiles(
+    provider: &State<sentinel::Sentinel>,
+    changes: &State<change::Change>,
+    product: &str,
+    zoom: u8,
+    x: u32,
+    y_with_extension: &str,
+    query: ChangeQuery<'_>,
+) -> Result<Option<ProductResponse>, BadRequest<String>> {
+    if !(tile::MIN_ZOOM..=tile::MAX_ZOOM).contains(&zoom) {
+        return Ok(None);
+    }
+    let product = change::Product::parse(product, query.band)
+        .map_err(|error| BadRequest(error.to_string()))?;
+    if provider.scripts().get(product.script()).is_none() {
+        return Ok(None);
+    }
+    let Some(y) = y_with_extension.strip_suffix(".png") else {
+        return Ok(None);
+    };
+    let Ok(y) = y.parse::<u32>() else {
+        return Ok(None);
+    };
+    if query.reference_from.is_none() && query.reference_before.is_none() {
+        return Err(BadRequest(
+            "reference_from and reference_to or reference_before are needed".into(),
+        ));
+    }
+    let reference = sentinel::ImageryOptions::parse(
+        query.cloud,
+        query.reference_from,
+        query.reference_to,
+        query.reference_before,
+        query.order,
+    )
+    .map_err(|error| BadRequest(error.to_string()))?;
+    let options = sentinel::ImageryOptions::parse(
+        query.cloud,
+        query.from,
+        query.to,
+        query.before,
+        query.order,
+    )
+    .map_err(|error| BadRequest(error.to_string()))?;
+    let comparison = change::Comparison {
+        product,
+        reference,
+        options,
+    };
+    log::info!(
+        "generating change tile {} {} {} {:?}",
+        zoom,
+        x,
+        y,
+        comparison
+    );
+    match changes.get(provider, zoom, x, y, &comparison).await {
+        Ok(path) => Ok(NamedFile::open(&path).await.ok().map(ProductResponse::Tile)),
+        Err(error) if error.is::<usage::BudgetExceeded>() => {
+            log::warn!("not fetching change tile {} {} {}: {}", zoom, x, y, error);
+            Ok(Some(ProductResponse::placeholder()))
+        }
+        Err(error) => {
+            log::error!("could not make change tile {} {} {}: {}", zoom, x, y, error);
+            Ok(None)
+        }
     }
-    let y = y_with_extension
-        .strip_suffix(".png")
-        .unwrap()
-        .parse::<u32>()
-        .unwrap();
-    log::info!("generating sentinel imagery tile {} {} {}", zoom, x, y);
-    let path = provider.get(zoom, x, y).await.unwrap();
-    NamedFile::open(&path).await.ok()
 }
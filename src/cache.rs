@@ -1,3 +1,4 @@
+use crate::processing;
 use crate::processing::ProcessingError;
 use anyhow::Result;
 use reqwest;
@@ -18,17 +19,41 @@ pub enum MaybePath {
     NotAvailable(PathBuf),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    /// the item does not exist upstream, retrying will not help
+    NotFound,
+    /// upstream is throttling or briefly failing, asking later may work
+    Retryable,
+    /// the access token was refused, asking with a new one may work
+    Unauthorized,
+}
+
 #[derive(Debug, Clone)]
 pub struct GeneratorError {
     message: String,
+    kind: ErrorKind,
 }
 
 impl GeneratorError {
     pub fn new(message: &str) -> Self {
         GeneratorError {
             message: message.into(),
+            kind: ErrorKind::Other,
+        }
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        GeneratorError {
+            message: message.into(),
+            kind: ErrorKind::NotFound,
         }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 }
 
 impl std::error::Error for GeneratorError {}
@@ -39,15 +64,30 @@ impl std::fmt::Display for GeneratorError {
 }
 impl From<ProcessingError> for GeneratorError {
     fn from(error: ProcessingError) -> Self {
+        let kind = match error.kind() {
+            processing::ErrorKind::Permanent => ErrorKind::Other,
+            processing::ErrorKind::Retryable => ErrorKind::Retryable,
+            processing::ErrorKind::Unauthorized => ErrorKind::Unauthorized,
+        };
         GeneratorError {
             message: format!("{}", error),
+            kind,
         }
     }
 }
 impl From<reqwest::Error> for GeneratorError {
     fn from(error: reqwest::Error) -> Self {
+        if error.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+            return GeneratorError::not_found(&format!("reqwest: {}", error));
+        }
+        ProcessingError::from(error).into()
+    }
+}
+impl From<serde_json::Error> for GeneratorError {
+    fn from(error: serde_json::Error) -> Self {
         GeneratorError {
-            message: format!("reqwest: {}", error),
+            message: format!("serde: {}", error),
+            kind: ErrorKind::Other,
         }
     }
 }
@@ -55,6 +95,7 @@ impl From<std::env::VarError> for GeneratorError {
     fn from(_: std::env::VarError) -> Self {
         GeneratorError {
             message: format!("env"),
+            kind: ErrorKind::Other,
         }
     }
 }
@@ -62,13 +103,39 @@ impl From<ToStrError> for GeneratorError {
     fn from(_: ToStrError) -> Self {
         GeneratorError {
             message: format!("tostr"),
+            kind: ErrorKind::Other,
         }
     }
 }
 impl From<std::io::Error> for GeneratorError {
-    fn from(_: std::io::Error) -> Self {
+    fn from(error: std::io::Error) -> Self {
+        GeneratorError {
+            message: format!("io: {}", error),
+            kind: ErrorKind::Other,
+        }
+    }
+}
+impl From<zip::result::ZipError> for GeneratorError {
+    fn from(error: zip::result::ZipError) -> Self {
         GeneratorError {
-            message: format!("io"),
+            message: format!("zip: {}", error),
+            kind: ErrorKind::Other,
+        }
+    }
+}
+impl From<anyhow::Error> for GeneratorError {
+    fn from(error: anyhow::Error) -> Self {
+        GeneratorError {
+            message: format!("{}", error),
+            kind: ErrorKind::Other,
+        }
+    }
+}
+impl From<tempfile::PersistError> for GeneratorError {
+    fn from(error: tempfile::PersistError) -> Self {
+        GeneratorError {
+            message: format!("persist: {}", error),
+            kind: ErrorKind::Other,
         }
     }
 }
@@ -76,6 +143,8 @@ impl From<std::io::Error> for GeneratorError {
 pub type CacheResult = StdResult<PathBuf, GeneratorError>;
 type SendBack = mpsc::Sender<CacheResult>;
 type GetBack = mpsc::Receiver<CacheResult>;
+/// Requests to a cache thread along with the generator to run on a miss
+pub type Sender = mpsc::Sender<(Request, Box<dyn FnOnce() -> CacheResult + Send>)>;
 
 #[derive(Debug)]
 pub struct Request {
@@ -111,14 +180,24 @@ where
     return rx.recv().unwrap();
 }
 
-pub fn run_cache(
-    mut cache: Cache,
-) -> mpsc::Sender<(Request, Box<dyn FnOnce() -> CacheResult + Send>)> {
+pub fn run_cache(mut cache: Cache) -> Sender {
     let (tx, rx) = mpsc::channel();
     thread::spawn(move || cache_thread(&mut cache, rx));
     return tx;
 }
 
+/// Ask a cache thread started by `run_cache` for an item, generating it if needed
+pub async fn ask<F>(tx: &Sender, key: PathBuf, generator: F) -> Result<PathBuf>
+where
+    F: FnOnce() -> CacheResult + Send + 'static,
+{
+    let (send_back, rx) = mpsc::channel();
+    tx.send((Request { key, send_back }, Box::new(generator)))
+        .unwrap();
+    // wait off the async runtime so other items can be requested meanwhile
+    Ok(tokio::task::spawn_blocking(move || rx.recv()).await???)
+}
+
 fn cache_thread(
     cache: &mut Cache,
     rx: mpsc::Receiver<(Request, Box<dyn FnOnce() -> CacheResult + Send>)>,
@@ -181,7 +260,8 @@ impl Cache {
         if let Some(path) = self.get(&get.key) {
             // immediately return item if in cache
             log::info!("return item from cache {:?}", get.key);
-            get.send_back.send(Ok(path.clone())).unwrap();
+            // the asker may have stopped waiting, which is fine
+            let _ = get.send_back.send(Ok(path.clone()));
         } else {
             if self.in_progress.get(&get.key).is_none() {
                 // execute generator if no one already generating this item
@@ -199,6 +279,20 @@ impl Cache {
         }
     }
 
+    /// Register an item written into the cache directory by someone else
+    pub fn adopt(&mut self, key: PathBuf) -> CacheResult {
+        let path = self.cache.join(&key);
+        if !path.is_file() {
+            return Err(GeneratorError::not_found(&format!(
+                "nothing to adopt at {:?}",
+                path
+            )));
+        }
+        log::info!("adopt item {:?} from {:?}", key, path);
+        self.insert(key, path.clone())?;
+        Ok(path)
+    }
+
     pub fn check(&mut self) {
         // get all finished generators
         let mut finished = Vec::new();
@@ -212,24 +306,33 @@ impl Cache {
             let handle = self.in_progress.remove(&key).unwrap();
             let result = handle.join().unwrap();
             for sender in self.to_return.remove(&key).unwrap() {
-                sender.send(result.clone()).unwrap();
+                let _ = sender.send(result.clone());
             }
             if let Ok(value) = result {
-                let metadata = fs::metadata(&value).unwrap();
-                let bytes = metadata.len();
-                self.size_bytes += bytes;
-                self.items.insert(
-                    key.clone(),
-                    Entry {
-                        path: value,
-                        bytes,
-                        created: metadata.created().unwrap(),
-                    },
-                );
+                self.insert(key, value).unwrap();
             }
         }
     }
 
+    /// Add an item which is already on disk
+    pub fn insert(&mut self, key: PathBuf, path: PathBuf) -> std::io::Result<()> {
+        let metadata = fs::metadata(&path)?;
+        let bytes = metadata.len() + sidecar_bytes(&path)?;
+        self.size_bytes += bytes;
+        let replaced = self.items.insert(
+            key,
+            Entry {
+                path,
+                bytes,
+                created: metadata.created()?,
+            },
+        );
+        if let Some(old) = replaced {
+            self.size_bytes -= old.bytes;
+        }
+        Ok(())
+    }
+
     pub fn cleanup(&mut self) {
         self.shrink();
         self.expire();
@@ -272,6 +375,9 @@ impl Cache {
             self.size_bytes,
         );
         self.size_bytes -= entry.bytes;
+        if let Some(sidecar) = sidecar(&entry.path) {
+            let _ = std::fs::remove_file(sidecar);
+        }
         std::fs::remove_file(entry.path).unwrap();
     }
 }
@@ -282,20 +388,38 @@ fn add_all(
     items: &mut ringmap::RingMap<PathBuf, Entry>,
 ) -> Result<u64> {
     let mut size = 0;
-    for maybe_entry in fs::read_dir(cache.join(subpath))? {
-        let entry = maybe_entry?;
+    let entries = fs::read_dir(cache.join(subpath))?.collect::<StdResult<Vec<_>, _>>()?;
+    // stems of the items here, a `.json` file sharing one is that item's sidecar
+    let stems: collections::HashSet<_> = entries
+        .iter()
+        .map(|entry| entry.path())
+        .filter(|path| !is_partial(path) && !is_json(path) && path.is_file())
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_os_string()))
+        .collect();
+    for entry in entries {
         let entry_path = entry.path();
-        if entry_path.is_dir() {
+        if is_partial(&entry_path) {
+            // left behind by an interrupted generator
+            continue;
+        } else if entry_path.is_dir() {
             size += add_all(cache, &entry_path.strip_prefix(cache)?, items)?;
+        } else if is_json(&entry_path)
+            && entry_path
+                .file_stem()
+                .is_some_and(|stem| stems.contains(stem))
+        {
+            // counted with the item it describes
+            continue;
         } else {
             let key = entry_path.strip_prefix(cache)?;
             let metadata = entry.metadata()?;
-            size += metadata.len();
+            let bytes = metadata.len() + sidecar_bytes(&entry_path)?;
+            size += bytes;
             items.insert(
                 key.to_path_buf(),
                 Entry {
                     path: entry_path,
-                    bytes: metadata.len(),
+                    bytes,
                     created: metadata.created()?,
                 },
             );
@@ -304,6 +428,34 @@ fn add_all(
     Ok(size)
 }
 
+/// The `.json` metadata written next to an item, if there is any
+///
+/// Sidecars are counted and evicted along with the item they describe.
+fn sidecar(path: &Path) -> Option<PathBuf> {
+    if is_json(path) {
+        return None;
+    }
+    Some(path.with_extension("json")).filter(|sidecar| sidecar.is_file())
+}
+
+fn sidecar_bytes(path: &Path) -> std::io::Result<u64> {
+    match sidecar(path) {
+        Some(sidecar) => Ok(fs::metadata(sidecar)?.len()),
+        None => Ok(0),
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+/// Hidden files and `.part` files are work in progress, not cache items
+fn is_partial(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') || name.ends_with(".part")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -346,6 +498,7 @@ mod tests {
         fs::write(dir.path().join("b"), "b").unwrap();
         fs::create_dir(dir.path().join("c")).unwrap();
         fs::write(dir.path().join("c").join("d"), "d").unwrap();
+        fs::write(dir.path().join(".e.zip.part"), "e").unwrap();
         let mut cache =
             Cache::from_existing_directory(dir.path().to_path_buf(), 10_000, 100, 600).unwrap();
         println!("{:?}", cache);
@@ -355,6 +508,34 @@ mod tests {
             .get(&Path::new("c").join("d"))
             .unwrap()
             .ends_with("c/d"));
+        assert!(cache.get(&".e.zip.part".into()).is_none());
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.png"), "png").unwrap();
+        fs::write(dir.path().join("a.json"), "{}").unwrap();
+        fs::write(dir.path().join("usage.json"), "{}").unwrap();
+        let mut cache =
+            Cache::from_existing_directory(dir.path().to_path_buf(), 10_000, 100, 600).unwrap();
+        assert!(cache.get(&"a.png".into()).is_some());
+        assert!(cache.get(&"a.json".into()).is_none());
+        assert!(cache.get(&"usage.json".into()).is_some());
+        assert_eq!(cache.size_bytes, 7);
+
+        fs::write(dir.path().join("b.png"), "png").unwrap();
+        fs::write(dir.path().join("b.json"), "{}").unwrap();
+        cache
+            .insert("b.png".into(), dir.path().join("b.png"))
+            .unwrap();
+        assert_eq!(cache.size_bytes, 12);
+
+        while cache.get(&"b.png".into()).is_some() {
+            cache.remove_oldest();
+        }
+        assert_eq!(cache.size_bytes, 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
     }
 
     #[test]
@@ -419,6 +600,35 @@ mod tests {
         assert!(!cache.cache.join("z").exists());
     }
 
+    #[test]
+    fn test_adopt_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache =
+            Cache::from_existing_directory(dir.path().to_path_buf(), 10_000, 100, 600).unwrap();
+        assert!(cache.adopt("a".into()).is_err());
+        // written after the cache was built, for example by an import
+        fs::write(dir.path().join("a"), "imported").unwrap();
+        assert!(cache.get(&"a".into()).is_none());
+        let result = cache.adopt("a".into()).unwrap();
+        assert!(result.ends_with("a"));
+        assert!(cache.get(&"a".into()).is_some());
+        assert_eq!(cache.size_bytes, 8);
+    }
+
+    #[test]
+    fn test_asker_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache =
+            Cache::from_existing_directory(dir.path().to_path_buf(), 10_000, 100, 600).unwrap();
+        drop(insert_item(&mut cache, "a", "a"));
+        while !cache.in_progress.is_empty() {
+            cache.check();
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        assert!(cache.get(&"a".into()).is_some());
+        drop(insert_item(&mut cache, "a", "a"));
+    }
+
     #[test]
     fn test_expire_cache_items_after_age_limit() {
         let dir = tempfile::tempdir().unwrap();
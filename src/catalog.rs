@@ -0,0 +1,221 @@
+use crate::elevation::CellBounds;
+use crate::processing::{ProcessingError, ProcessingResult};
+use crate::sentinel::ImageryOptions;
+use crate::token;
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const URL: &str = "https://sh.dataspace.copernicus.eu/api/v1/catalog/1.0.0/search";
+/// Collection searched when none is given
+pub const DEFAULT_COLLECTION: &str = "sentinel-2-l2a";
+/// Scenes requested per page of results
+const PAGE_LIMIT: u32 = 100;
+/// Pages followed before giving up on a search
+const MAX_PAGES: usize = 20;
+
+/// A scene found by a catalog search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Acquisition {
+    pub id: String,
+    /// RFC 3339 acquisition time
+    pub datetime: String,
+    /// percent of the scene which is cloud, none for radar
+    pub cloud_cover: Option<f64>,
+    /// GeoJSON footprint of the scene
+    pub footprint: serde_json::Value,
+}
+
+impl Acquisition {
+    fn from_feature(feature: &serde_json::Value) -> Option<Self> {
+        Some(Acquisition {
+            id: feature["id"].as_str()?.to_string(),
+            datetime: feature["properties"]["datetime"].as_str()?.to_string(),
+            cloud_cover: feature["properties"]["eo:cloud_cover"].as_f64(),
+            footprint: feature["geometry"].clone(),
+        })
+    }
+
+    /// Acquisition date like `2025-02-01`
+    pub fn date(&self) -> &str {
+        self.datetime.get(..10).unwrap_or(&self.datetime)
+    }
+}
+
+/// The acquisitions as a GeoJSON feature collection of their footprints
+pub fn to_geojson(acquisitions: &[Acquisition]) -> serde_json::Value {
+    let features: Vec<serde_json::Value> = acquisitions
+        .iter()
+        .map(|acquisition| {
+            serde_json::json!({
+                "type": "Feature",
+                "id": acquisition.id,
+                "properties": {
+                    "date": acquisition.date(),
+                    "datetime": acquisition.datetime,
+                    "cloud_cover": acquisition.cloud_cover,
+                },
+                "geometry": acquisition.footprint,
+            })
+        })
+        .collect();
+    serde_json::json!({"type": "FeatureCollection", "features": features})
+}
+
+/// Client for the Sentinel Hub catalog, a STAC API listing which scenes exist
+pub struct Catalog {
+    url: String,
+    token_generator: token::Generator,
+}
+
+impl Catalog {
+    pub fn new<T: Into<String>>(url: T, token_generator: token::Generator) -> Self {
+        Catalog {
+            url: url.into(),
+            token_generator,
+        }
+    }
+
+    /// Find scenes of a collection over the bounds in the dates and cloud
+    /// coverage of the options, oldest first
+    pub async fn search(
+        &self,
+        collection: &str,
+        bounds: &CellBounds,
+        options: &ImageryOptions,
+    ) -> ProcessingResult<Vec<Acquisition>> {
+        let (start, end) = options.time_range(OffsetDateTime::now_utc());
+        let mut body = serde_json::json!({
+            "collections": [collection],
+            "bbox": [
+                bounds.min_longitude,
+                bounds.min_latitude,
+                bounds.max_longitude,
+                bounds.max_latitude
+            ],
+            "datetime": format!(
+                "{}/{}",
+                start.format(&Rfc3339).unwrap_or_default(),
+                end.format(&Rfc3339).unwrap_or_default()
+            ),
+            "limit": PAGE_LIMIT,
+        });
+        if !collection.starts_with("sentinel-1") {
+            body["filter"] = format!("eo:cloud_cover <= {}", options.max_cloud_coverage).into();
+            body["filter-lang"] = "cql2-text".into();
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        let mut acquisitions = Vec::new();
+        for _ in 0..MAX_PAGES {
+            let token = self.token_generator.get().await?;
+            log::debug!("catalog search {}", body);
+            let response = client
+                .post(&self.url)
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .json(&body)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                let text = response.text().await?;
+                return Err(ProcessingError::new(&format!("catalog error: {}", text)));
+            }
+            let page: serde_json::Value = response.json().await?;
+            let features = page["features"]
+                .as_array()
+                .ok_or(ProcessingError::new("catalog response has no features"))?;
+            acquisitions.extend(features.iter().filter_map(Acquisition::from_feature));
+            match page["context"]["next"].as_u64() {
+                Some(next) => body["next"] = next.into(),
+                None => break,
+            }
+        }
+        acquisitions.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        Ok(acquisitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::standin;
+
+    fn feature(id: &str, datetime: &str, cloud: f64) -> serde_json::Value {
+        serde_json::json!({
+            "type": "Feature",
+            "id": id,
+            "properties": {"datetime": datetime, "eo:cloud_cover": cloud},
+            "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 0]]]}
+        })
+    }
+
+    #[test]
+    fn test_search() {
+        let token = r#"{"access_token": "token", "expires_in": 600}"#;
+        let first = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [feature("b", "2025-01-20T18:55:39Z", 12.5)],
+            "context": {"next": 1, "limit": 100, "returned": 1}
+        });
+        let second = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [feature("a", "2025-01-10T18:55:39Z", 3.0)],
+            "context": {"limit": 100, "returned": 1}
+        });
+        let (address, server) = standin::serve(vec![
+            standin::Reply::json(token),
+            standin::Reply::json(first.to_string()),
+            standin::Reply::json(second.to_string()),
+        ]);
+        let catalog = Catalog::new(
+            format!("{}/search", address),
+            token::Generator::new(format!("{}/token", address)).with_credentials("id", "secret"),
+        );
+        let bounds = CellBounds {
+            min_longitude: -120.0,
+            min_latitude: 49.0,
+            max_longitude: -119.0,
+            max_latitude: 50.0,
+        };
+        let options = ImageryOptions::parse(
+            Some(20.0),
+            Some("2025-01-01"),
+            Some("2025-01-31"),
+            None,
+            None,
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let acquisitions = runtime
+            .block_on(catalog.search(DEFAULT_COLLECTION, &bounds, &options))
+            .unwrap();
+        let bodies: Vec<String> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|received| received.body)
+            .collect();
+
+        assert!(bodies[0].contains("client_id=id"));
+        assert_eq!(acquisitions.len(), 2);
+        assert_eq!(acquisitions[0].id, "a");
+        assert_eq!(acquisitions[0].date(), "2025-01-10");
+        assert_eq!(acquisitions[1].cloud_cover, Some(12.5));
+        let request: serde_json::Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(request["collections"][0], "sentinel-2-l2a");
+        assert_eq!(
+            request["datetime"],
+            "2025-01-01T00:00:00Z/2025-02-01T00:00:00Z"
+        );
+        assert_eq!(request["filter"], "eo:cloud_cover <= 20");
+        let request: serde_json::Value = serde_json::from_str(&bodies[2]).unwrap();
+        assert_eq!(request["next"], 1);
+
+        let geojson = to_geojson(&acquisitions);
+        assert_eq!(geojson["features"][1]["properties"]["date"], "2025-01-20");
+        assert_eq!(geojson["features"][0]["geometry"]["type"], "Polygon");
+    }
+}
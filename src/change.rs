@@ -0,0 +1,276 @@
+use crate::cache;
+use crate::processing::{ProcessingError, ProcessingResult};
+use crate::sentinel;
+use anyhow::Result;
+use image::{ImageReader, Rgba, RgbaImage};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Script whose grey encodes NDSI, see `evalscripts/ndsi-index.js`
+pub const NDSI_SCRIPT: &str = "ndsi-index";
+/// Change in NDSI shown at full colour
+const NDSI_RANGE: f64 = 0.5;
+/// Change in a band, as a fraction of its full scale, shown at full colour
+const BAND_RANGE: f64 = 0.25;
+
+/// Colour a value from -1 (blue) through 0 (clear) to 1 (red)
+pub fn diverging(value: f64) -> Rgba<u8> {
+    let value = value.clamp(-1.0, 1.0);
+    let fade = (255.0 * (1.0 - value.abs())) as u8;
+    let alpha = (255.0 * value.abs()) as u8;
+    if value >= 0.0 {
+        Rgba([255, fade, fade, alpha])
+    } else {
+        Rgba([fade, fade, 255, alpha])
+    }
+}
+
+/// Colour the change in value from the first to the second image
+///
+/// `decode` reads the value of a pixel, none where there is no data, and a
+/// change of `range` is shown at full colour.
+pub fn difference<F>(
+    first: &Path,
+    second: &Path,
+    output: &Path,
+    decode: F,
+    range: f64,
+) -> ProcessingResult<()>
+where
+    F: Fn(&Rgba<u8>) -> Option<f64>,
+{
+    let first = ImageReader::open(first)?.decode()?.to_rgba8();
+    let second = ImageReader::open(second)?.decode()?.to_rgba8();
+    if first.dimensions() != second.dimensions() {
+        return Err(ProcessingError::new("compared images differ in size"));
+    }
+    let mut result = RgbaImage::new(first.width(), first.height());
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        if let (Some(before), Some(after)) = (
+            decode(first.get_pixel(x, y)),
+            decode(second.get_pixel(x, y)),
+        ) {
+            *pixel = diverging((after - before) / range);
+        }
+    }
+    result.save_with_format(output, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// NDSI of a pixel of the `ndsi-index` layer
+pub fn decode_ndsi(pixel: &Rgba<u8>) -> Option<f64> {
+    if pixel[3] == 0 || pixel[0] == 0 {
+        return None;
+    }
+    Some((pixel[0] as f64 - 1.0) / 127.0 - 1.0)
+}
+
+/// What is compared between the two dates
+#[derive(Debug, Clone, PartialEq)]
+pub enum Product {
+    /// gain (red) or loss (blue) of snow
+    Ndsi,
+    /// one output band of a script
+    Band(String, usize),
+}
+
+impl Product {
+    /// `ndsi` for the NDSI change, otherwise the difference in `band` of the
+    /// named script's output
+    pub fn parse(name: &str, band: Option<usize>) -> std::result::Result<Self, ProcessingError> {
+        match (name, band) {
+            ("ndsi", None) => Ok(Product::Ndsi),
+            ("ndsi", Some(_)) => Err(ProcessingError::new("ndsi change has no bands")),
+            (_, Some(band)) if band > 2 => Err(ProcessingError::new("band should be 0, 1 or 2")),
+            (name, band) => Ok(Product::Band(name.to_string(), band.unwrap_or(0))),
+        }
+    }
+
+    /// Script fetched for each date
+    pub fn script(&self) -> &str {
+        match self {
+            Product::Ndsi => NDSI_SCRIPT,
+            Product::Band(script, _) => script,
+        }
+    }
+
+    fn key(&self) -> PathBuf {
+        match self {
+            Product::Ndsi => PathBuf::from("ndsi"),
+            Product::Band(script, band) => PathBuf::from(script).join(format!("band-{}", band)),
+        }
+    }
+
+    fn compare(&self, first: &Path, second: &Path, output: &Path) -> ProcessingResult<()> {
+        match self {
+            Product::Ndsi => difference(first, second, output, decode_ndsi, NDSI_RANGE),
+            Product::Band(_, band) => {
+                let band = *band;
+                let decode = |pixel: &Rgba<u8>| {
+                    if pixel[3] == 0 {
+                        None
+                    } else {
+                        Some(pixel[band] as f64 / 255.0)
+                    }
+                };
+                difference(first, second, output, decode, BAND_RANGE)
+            }
+        }
+    }
+}
+
+/// A product compared between two imagery searches
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub product: Product,
+    /// search for the earlier imagery
+    pub reference: sentinel::ImageryOptions,
+    pub options: sentinel::ImageryOptions,
+}
+
+impl Comparison {
+    fn key(&self) -> PathBuf {
+        self.product
+            .key()
+            .join(self.reference.key())
+            .join(self.options.key())
+    }
+}
+
+/// Tiles of the change in imagery between two searches
+pub struct Change {
+    cache_dir: PathBuf,
+    cache_tx: mpsc::Sender<(
+        cache::Request,
+        Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    )>,
+}
+
+impl Change {
+    pub fn new(cache_dir: PathBuf) -> Self {
+        let cache = cache::Cache::from_existing_directory(
+            cache_dir.clone(),
+            10_000_000_000,
+            100_000_000,
+            86400,
+        )
+        .unwrap();
+        Change {
+            cache_dir,
+            cache_tx: cache::run_cache(cache),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        sentinel: &sentinel::Sentinel,
+        zoom: u8,
+        x: u32,
+        y: u32,
+        comparison: &Comparison,
+    ) -> Result<PathBuf> {
+        let key = comparison
+            .key()
+            .join(format!("{}", zoom))
+            .join(format!("{}", x))
+            .join(format!("{}.png", y));
+        let output = self.cache_dir.join(&key);
+        // each date is cached by the imagery provider so sliding one end of
+        // the comparison only fetches the new date
+        let script = comparison.product.script();
+        let first = sentinel
+            .get(zoom, x, y, script, &comparison.reference)
+            .await?;
+        let second = sentinel
+            .get(zoom, x, y, script, &comparison.options)
+            .await?;
+        let product = comparison.product.clone();
+        let generator = move || {
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            product.compare(&first, &second, &output)?;
+            Ok(output)
+        };
+        cache::ask(&self.cache_tx, key, generator).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save(path: &Path, pixels: &[Rgba<u8>]) {
+        let mut image = RgbaImage::new(pixels.len() as u32, 1);
+        for (x, pixel) in pixels.iter().enumerate() {
+            image.put_pixel(x as u32, 0, *pixel);
+        }
+        image.save(path).unwrap();
+    }
+
+    #[test]
+    fn test_product() {
+        assert_eq!(Product::parse("ndsi", None).unwrap(), Product::Ndsi);
+        assert_eq!(Product::Ndsi.script(), "ndsi-index");
+        assert_eq!(
+            Product::parse("true-color", None).unwrap(),
+            Product::Band("true-color".into(), 0)
+        );
+        assert!(Product::parse("ndsi", Some(1)).is_err());
+        assert!(Product::parse("true-color", Some(3)).is_err());
+        assert_eq!(diverging(0.0)[3], 0);
+        assert_eq!(diverging(2.0), Rgba([255, 0, 0, 255]));
+        assert_eq!(diverging(-1.0), Rgba([0, 0, 255, 255]));
+        assert_eq!(decode_ndsi(&Rgba([128, 128, 128, 255])), Some(0.0));
+        assert_eq!(decode_ndsi(&Rgba([0, 0, 0, 0])), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.png");
+        let second = dir.path().join("second.png");
+        let output = dir.path().join("change.png");
+        // snow lost, snow gained and cloud in the second image
+        save(
+            &first,
+            &[
+                Rgba([255, 255, 255, 255]),
+                Rgba([128, 128, 128, 255]),
+                Rgba([200, 200, 200, 255]),
+            ],
+        );
+        save(
+            &second,
+            &[
+                Rgba([128, 128, 128, 255]),
+                Rgba([255, 255, 255, 255]),
+                Rgba([0, 0, 0, 0]),
+            ],
+        );
+        Product::Ndsi.compare(&first, &second, &output).unwrap();
+        let result = ImageReader::open(&output)
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(*result.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(*result.get_pixel(1, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(result.get_pixel(2, 0)[3], 0);
+
+        save(&first, &[Rgba([0, 100, 0, 255])]);
+        save(&second, &[Rgba([0, 100 + 32, 0, 255])]);
+        Product::Band("true-color".into(), 1)
+            .compare(&first, &second, &output)
+            .unwrap();
+        let result = ImageReader::open(&output)
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        // half of the full colour change
+        assert_eq!(result.get_pixel(0, 0)[0], 255);
+        assert!((126..=128).contains(&result.get_pixel(0, 0)[3]));
+    }
+}
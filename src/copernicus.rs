@@ -0,0 +1,130 @@
+use crate::cache;
+use crate::elevation;
+use crate::tile;
+use std::io;
+use std::path::Path;
+use std::time;
+use tempfile::NamedTempFile;
+
+const URL: &str = "https://copernicus-dem-30m.s3.amazonaws.com";
+
+/// Copernicus GLO-30 has no voids, this is the no data value of its rasters
+pub const VOID_VALUE: f64 = -32767.0;
+
+/// Elevation cells from the Copernicus GLO-30 DEM on AWS open data
+///
+/// Cells are cloud optimized GeoTIFFs which need no credentials to download.
+#[derive(Default)]
+pub struct Copernicus {}
+
+impl Copernicus {
+    pub fn new() -> Self {
+        Copernicus {}
+    }
+}
+
+impl elevation::ElevationSource for Copernicus {
+    fn name(&self) -> &str {
+        "copernicus"
+    }
+
+    fn cell_id(&self, point: &tile::GeoPoint) -> String {
+        copernicus_id(point)
+    }
+
+    fn cell_bounds(&self, id: &str) -> Option<elevation::CellBounds> {
+        copernicus_bounds(id)
+    }
+
+    fn void_value(&self) -> f64 {
+        VOID_VALUE
+    }
+
+    fn fetch_cell(&self, output_directory: &Path, id: &str) -> cache::CacheResult {
+        let stem = id.strip_suffix(".tif").ok_or(cache::GeneratorError::new(
+            "key did not contain .tif suffix",
+        ))?;
+        let url = format!("{}/{}/{}", URL, stem, id);
+        log::info!("downloading copernicus dem {}", id);
+        let client = reqwest::blocking::Client::builder()
+            .timeout(time::Duration::from_secs(180))
+            .build()?;
+        let mut response = client.get(&url).send()?.error_for_status()?;
+        let output = output_directory.join(id);
+        // hidden until complete so an interrupted download is never cached
+        let mut temporary = NamedTempFile::new_in(output_directory)?;
+        io::copy(&mut response, &mut temporary)?;
+        temporary.persist(&output)?;
+        Ok(output)
+    }
+}
+
+/// Name of the GLO-30 cell containing a point, like
+/// `Copernicus_DSM_COG_10_N49_00_W120_00_DEM.tif`
+pub fn copernicus_id(point: &tile::GeoPoint) -> String {
+    let latitude = point.latitude.floor();
+    let longitude = point.longitude.floor();
+    let north = if latitude >= 0.0 { "N" } else { "S" };
+    let east = if longitude >= 0.0 { "E" } else { "W" };
+    format!(
+        "Copernicus_DSM_COG_10_{}{:02}_00_{}{:03}_00_DEM.tif",
+        north,
+        latitude.abs(),
+        east,
+        longitude.abs()
+    )
+}
+
+pub fn copernicus_bounds(id: &str) -> Option<elevation::CellBounds> {
+    let parts: Vec<&str> = id.split('_').collect();
+    if parts.len() != 9 || !parts[4].is_ascii() || !parts[6].is_ascii() {
+        return None;
+    }
+    let latitude: f64 = parts[4].get(1..)?.parse().ok()?;
+    let longitude: f64 = parts[6].get(1..)?.parse().ok()?;
+    let latitude = match parts[4].get(0..1)? {
+        "N" => latitude,
+        "S" => -latitude,
+        _ => return None,
+    };
+    let longitude = match parts[6].get(0..1)? {
+        "E" => longitude,
+        "W" => -longitude,
+        _ => return None,
+    };
+    Some(elevation::CellBounds {
+        min_longitude: longitude,
+        min_latitude: latitude,
+        max_longitude: longitude + 1.0,
+        max_latitude: latitude + 1.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id() {
+        let id = copernicus_id(&tile::GeoPoint {
+            latitude: 49.4923,
+            longitude: -119.59018,
+        });
+        assert_eq!(id, "Copernicus_DSM_COG_10_N49_00_W120_00_DEM.tif");
+        let id = copernicus_id(&tile::GeoPoint {
+            latitude: -4.11909,
+            longitude: 22.55864,
+        });
+        assert_eq!(id, "Copernicus_DSM_COG_10_S05_00_E022_00_DEM.tif");
+    }
+
+    #[test]
+    fn test_bounds() {
+        let bounds = copernicus_bounds("Copernicus_DSM_COG_10_N49_00_W120_00_DEM.tif").unwrap();
+        assert_eq!(bounds.min_longitude, -120.0);
+        assert_eq!(bounds.min_latitude, 49.0);
+        assert_eq!(bounds.max_longitude, -119.0);
+        assert_eq!(bounds.max_latitude, 50.0);
+        assert!(copernicus_bounds("N49W120.hgt").is_none());
+    }
+}
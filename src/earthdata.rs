@@ -0,0 +1,198 @@
+use crate::cache;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+pub const MACHINE: &str = "urs.earthdata.nasa.gov";
+
+/// Credentials for NASA Earthdata Login
+#[derive(Clone)]
+pub enum Credentials {
+    Login {
+        username: String,
+        password: String,
+    },
+    /// an Earthdata user token sent as a bearer token
+    Token(String),
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // never log secrets
+        match self {
+            Credentials::Login { username, .. } => write!(f, "Login({})", username),
+            Credentials::Token(_) => write!(f, "Token"),
+        }
+    }
+}
+
+impl Credentials {
+    /// Read credentials from the environment, falling back to `.netrc`
+    ///
+    /// `FLYTILE_EARTHDATA_TOKEN` takes precedence, then
+    /// `FLYTILE_EARTHDATA_USERNAME` with `FLYTILE_EARTHDATA_PASSWORD` (or the
+    /// older `FLYTILE_SRTM_PASSWORD`), then the entry for
+    /// urs.earthdata.nasa.gov in the file named by `NETRC` or `~/.netrc`.
+    pub fn from_env() -> Result<Self, LoginError> {
+        if let Ok(token) = env::var("FLYTILE_EARTHDATA_TOKEN") {
+            return Ok(Credentials::Token(token));
+        }
+        if let Ok(username) = env::var("FLYTILE_EARTHDATA_USERNAME") {
+            let password = env::var("FLYTILE_EARTHDATA_PASSWORD")
+                .or(env::var("FLYTILE_SRTM_PASSWORD"))
+                .map_err(|_| LoginError::MissingCredentials)?;
+            return Ok(Credentials::Login { username, password });
+        }
+        let path = match env::var("NETRC") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".netrc"),
+        };
+        if let Ok(text) = fs::read_to_string(&path) {
+            if let Some((username, password)) = read_netrc(&text, MACHINE) {
+                log::debug!("using earthdata credentials from {:?}", path);
+                return Ok(Credentials::Login { username, password });
+            }
+        }
+        Err(LoginError::MissingCredentials)
+    }
+
+    pub fn authorize(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::blocking::RequestBuilder {
+        match self {
+            Credentials::Login { username, password } => {
+                request.basic_auth(username, Some(password))
+            }
+            Credentials::Token(token) => request.bearer_auth(token),
+        }
+    }
+}
+
+/// Find the login and password for a machine in the contents of a netrc file
+pub fn read_netrc(text: &str, machine: &str) -> Option<(String, String)> {
+    let mut tokens = text.split_whitespace();
+    let mut in_machine = false;
+    let mut login = None;
+    let mut password = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                if in_machine {
+                    break;
+                }
+                in_machine = tokens.next() == Some(machine);
+            }
+            "default" => {
+                if in_machine {
+                    break;
+                }
+                in_machine = true;
+            }
+            "login" if in_machine => login = tokens.next().map(String::from),
+            "password" if in_machine => password = tokens.next().map(String::from),
+            _ => {}
+        }
+    }
+    Some((login?, password?))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginError {
+    MissingCredentials,
+    /// Earthdata rejected the username and password or token
+    BadCredentials,
+    /// the account has not accepted the EULA or authorized the application,
+    /// holds the url to visit to do so
+    EulaNotAccepted(String),
+    NotFound(String),
+    Network(String),
+    Http(String),
+}
+
+impl std::error::Error for LoginError {}
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoginError::MissingCredentials => write!(
+                f,
+                "no earthdata credentials, set FLYTILE_EARTHDATA_USERNAME and \
+                 FLYTILE_EARTHDATA_PASSWORD, FLYTILE_EARTHDATA_TOKEN or add {} to .netrc",
+                MACHINE
+            ),
+            LoginError::BadCredentials => write!(f, "earthdata rejected the credentials"),
+            LoginError::EulaNotAccepted(url) => write!(
+                f,
+                "earthdata account has not accepted the EULA or approved the application, visit {}",
+                url
+            ),
+            LoginError::NotFound(url) => write!(f, "not found: {}", url),
+            LoginError::Network(message) => write!(f, "network error: {}", message),
+            LoginError::Http(message) => write!(f, "http error: {}", message),
+        }
+    }
+}
+impl From<reqwest::Error> for LoginError {
+    fn from(error: reqwest::Error) -> Self {
+        match error.status() {
+            Some(reqwest::StatusCode::UNAUTHORIZED) => LoginError::BadCredentials,
+            Some(reqwest::StatusCode::NOT_FOUND) => {
+                LoginError::NotFound(error.url().map(|url| url.to_string()).unwrap_or_default())
+            }
+            Some(_) => LoginError::Http(format!("{}", error)),
+            None => LoginError::Network(format!("{}", error)),
+        }
+    }
+}
+impl From<LoginError> for cache::GeneratorError {
+    fn from(error: LoginError) -> Self {
+        match error {
+            LoginError::NotFound(_) => cache::GeneratorError::not_found(&format!("{}", error)),
+            _ => cache::GeneratorError::new(&format!("{}", error)),
+        }
+    }
+}
+
+/// True if an Earthdata redirect is asking the user to accept terms
+pub fn is_eula_redirect(location: &str) -> bool {
+    let location = location.to_lowercase();
+    location.contains("eula") || location.contains("approve_app")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_netrc() {
+        let text = "machine example.com login someone password secret\n\
+                    machine urs.earthdata.nasa.gov\n  login me\n  password hunter2\n\
+                    default login anonymous password guest\n";
+        assert_eq!(
+            read_netrc(text, MACHINE),
+            Some(("me".to_string(), "hunter2".to_string()))
+        );
+        assert_eq!(
+            read_netrc(text, "example.com"),
+            Some(("someone".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            read_netrc(text, "other.org"),
+            Some(("anonymous".to_string(), "guest".to_string()))
+        );
+        assert_eq!(read_netrc("machine example.com login a", MACHINE), None);
+    }
+
+    #[test]
+    fn test_eula_redirect() {
+        assert!(is_eula_redirect(
+            "https://urs.earthdata.nasa.gov/approve_app?client_id=abc"
+        ));
+        assert!(is_eula_redirect(
+            "https://urs.earthdata.nasa.gov/accept_eula?eula_id=LPDAAC"
+        ));
+        assert!(!is_eula_redirect(
+            "https://urs.earthdata.nasa.gov/oauth/authorize?client_id=abc"
+        ));
+    }
+}
@@ -0,0 +1,765 @@
+use crate::cache;
+use crate::copernicus;
+use crate::local;
+use crate::srtm;
+use crate::tile;
+use anyhow::Result;
+use futures::future;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use tokio;
+
+const DEFAULT_PARALLEL_DOWNLOADS: usize = 4;
+/// enough for any tile from zoom 8 up
+const DEFAULT_MAX_CELLS: usize = 16;
+
+/// Geographic extent of a single elevation cell in degrees
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellBounds {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+impl CellBounds {
+    /// Extent of a map tile, which is a box in longitude and latitude
+    pub fn from_tile(zoom: u8, x: u32, y: u32) -> Self {
+        let bounds = tile::tile_bounds(zoom, x, y);
+        CellBounds {
+            min_longitude: bounds.north_west.longitude,
+            min_latitude: bounds.south_east.latitude,
+            max_longitude: bounds.south_east.longitude,
+            max_latitude: bounds.north_west.latitude,
+        }
+    }
+}
+
+/// A provider of elevation data split into cells on a regular grid
+///
+/// Implementations only know how to name and retrieve their cells, caching
+/// and tile assembly are handled by `Elevation`.
+pub trait ElevationSource: Send + Sync {
+    /// short name of the source, also used as its cache directory
+    fn name(&self) -> &str;
+
+    /// id of the cell containing the given point, used as the cache key
+    fn cell_id(&self, point: &tile::GeoPoint) -> String;
+
+    fn cell_bounds(&self, id: &str) -> Option<CellBounds>;
+
+    /// value used in the source files to mark missing data
+    fn void_value(&self) -> f64;
+
+    /// nominal pixel size in meters, one arc second unless overridden
+    fn resolution(&self) -> f64 {
+        30.0
+    }
+
+    /// retrieve a cell into the output directory, blocking until done
+    fn fetch_cell(&self, output_directory: &Path, id: &str) -> cache::CacheResult;
+}
+
+/// Select an elevation source from the environment
+///
+/// `FLYTILE_ELEVATION_SOURCE` may be `srtm` (the default), `copernicus` or
+/// `local`, the last of which reads files from `FLYTILE_ELEVATION_DIR`.
+pub fn source_from_env() -> Result<Arc<dyn ElevationSource>> {
+    source_by_name(&env::var("FLYTILE_ELEVATION_SOURCE").unwrap_or("srtm".into()))
+}
+
+pub fn source_by_name(name: &str) -> Result<Arc<dyn ElevationSource>> {
+    match name {
+        "srtm" => Ok(Arc::new(srtm::SRTM::from_env())),
+        "copernicus" => Ok(Arc::new(copernicus::Copernicus::new())),
+        "local" => {
+            let directory = env::var("FLYTILE_ELEVATION_DIR")
+                .map_err(|_| anyhow!("FLYTILE_ELEVATION_DIR is required for local elevation"))?;
+            Ok(Arc::new(local::LocalDirectory::new(directory.into())))
+        }
+        _ => Err(anyhow!("unknown elevation source {}", name)),
+    }
+}
+
+/// How voids in elevation cells are filled before they are used
+pub enum VoidFill {
+    None,
+    /// inverse distance weighted interpolation from the void edge, searching
+    /// up to `max_distance` pixels
+    Interpolate {
+        max_distance: u32,
+    },
+    /// take values from another source resampled onto the cell grid
+    Secondary(Arc<Elevation>),
+}
+
+/// Select a void fill strategy from the environment
+///
+/// `FLYTILE_VOID_FILL` may be `interpolate` (the default, with a search
+/// distance from `FLYTILE_VOID_FILL_DISTANCE`), `secondary` (using the source
+/// named by `FLYTILE_VOID_FILL_SOURCE`, `copernicus` by default) or `none`.
+pub fn void_fill_from_env(cache: &Path) -> Result<VoidFill> {
+    let name = env::var("FLYTILE_VOID_FILL").unwrap_or("interpolate".into());
+    match name.as_str() {
+        "none" => Ok(VoidFill::None),
+        "interpolate" => {
+            let max_distance = match env::var("FLYTILE_VOID_FILL_DISTANCE") {
+                Ok(value) => value.parse()?,
+                Err(_) => 100,
+            };
+            Ok(VoidFill::Interpolate { max_distance })
+        }
+        "secondary" => {
+            let source = source_by_name(
+                &env::var("FLYTILE_VOID_FILL_SOURCE").unwrap_or("copernicus".into()),
+            )?;
+            Ok(VoidFill::Secondary(Arc::new(Elevation::new(
+                cache.join(source.name()),
+                source,
+            ))))
+        }
+        _ => Err(anyhow!("unknown void fill strategy {}", name)),
+    }
+}
+
+/// An elevation cell ready for use
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub id: String,
+    pub path: PathBuf,
+    /// fraction of the cell which is void in the source data
+    pub void_fraction: f64,
+    /// true if voids were filled by interpolation or from a secondary source
+    pub interpolated: bool,
+}
+
+/// Record of which cells exist upstream
+///
+/// Built as cells are requested so cells which do not exist (for example over
+/// the ocean) are only requested once. A shipped index can be marked
+/// `complete`, in which case any cell not listed as present is missing.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CellIndex {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    complete: bool,
+    #[serde(default)]
+    present: BTreeSet<String>,
+    #[serde(default)]
+    absent: BTreeSet<String>,
+    /// void fraction of present cells, filled in as cells are first read
+    #[serde(default)]
+    voids: BTreeMap<String, f64>,
+}
+
+impl CellIndex {
+    pub fn load(path: PathBuf) -> Self {
+        let mut index = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|error| {
+                log::warn!("ignoring invalid cell index {:?}: {}", path, error);
+                CellIndex::default()
+            }),
+            Err(_) => CellIndex::default(),
+        };
+        log::info!(
+            "loaded cell index {:?} with {} present and {} absent cells",
+            path,
+            index.present.len(),
+            index.absent.len()
+        );
+        index.path = path;
+        index
+    }
+
+    /// Merges in the saved index before writing, so cells recorded by another
+    /// process since this one was loaded (such as an import) are kept
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if let Ok(text) = fs::read_to_string(&self.path) {
+            match serde_json::from_str(&text) {
+                Ok(saved) => self.merge(saved),
+                Err(error) => log::warn!("replacing invalid cell index {:?}: {}", self.path, error),
+            }
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// a cell present in either index is present, it has been fetched or imported
+    fn merge(&mut self, other: CellIndex) {
+        self.complete |= other.complete;
+        for id in other.present {
+            self.mark_present(&id);
+        }
+        for id in other.absent {
+            if !self.present.contains(&id) {
+                self.absent.insert(id);
+            }
+        }
+        for (id, fraction) in other.voids {
+            self.voids.entry(id).or_insert(fraction);
+        }
+    }
+
+    pub fn is_absent(&self, id: &str) -> bool {
+        self.absent.contains(id) || (self.complete && !self.present.contains(id))
+    }
+
+    /// returns true if this changed the index
+    pub fn mark_present(&mut self, id: &str) -> bool {
+        self.absent.remove(id);
+        self.present.insert(id.to_string())
+    }
+
+    /// returns true if this changed the index
+    pub fn mark_absent(&mut self, id: &str) -> bool {
+        self.present.remove(id);
+        self.absent.insert(id.to_string())
+    }
+
+    pub fn void_fraction(&self, id: &str) -> Option<f64> {
+        self.voids.get(id).copied()
+    }
+
+    /// returns true if this changed the index
+    pub fn set_void_fraction(&mut self, id: &str, fraction: f64) -> bool {
+        self.voids.insert(id.to_string(), fraction) != Some(fraction)
+    }
+}
+
+/// path of the cell index kept next to a source's cache directory
+pub fn index_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.with_extension("cells.json")
+}
+
+pub struct Elevation {
+    source: Arc<dyn ElevationSource>,
+    index: Mutex<CellIndex>,
+    void_fill: VoidFill,
+    cache_dir: PathBuf,
+    cache_tx: mpsc::Sender<(
+        cache::Request,
+        Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    )>,
+    download_limit: tokio::sync::Semaphore,
+    max_cells: usize,
+}
+
+impl Elevation {
+    pub fn new(cache_dir: PathBuf, source: Arc<dyn ElevationSource>) -> Self {
+        let cache = cache::Cache::from_existing_directory(
+            cache_dir.clone(),
+            10_000_000_000,
+            100_000_000,
+            86400 * 365,
+        )
+        .unwrap();
+        let tx = cache::run_cache(cache);
+        Elevation {
+            source,
+            index: Mutex::new(CellIndex::load(index_path(&cache_dir))),
+            void_fill: VoidFill::None,
+            cache_dir,
+            cache_tx: tx,
+            download_limit: tokio::sync::Semaphore::new(DEFAULT_PARALLEL_DOWNLOADS),
+            max_cells: DEFAULT_MAX_CELLS,
+        }
+    }
+
+    /// Build the configured source, void fill and limits from the environment
+    ///
+    /// See `source_from_env` and `void_fill_from_env`, limits are read from
+    /// `FLYTILE_ELEVATION_PARALLEL` and `FLYTILE_ELEVATION_MAX_CELLS`.
+    pub fn from_env(cache: &Path) -> Result<Self> {
+        let source = source_from_env()?;
+        let mut elevation = Elevation::new(cache.join(source.name()), source)
+            .with_void_fill(void_fill_from_env(cache)?);
+        if let Ok(value) = env::var("FLYTILE_ELEVATION_PARALLEL") {
+            elevation = elevation.with_parallel_downloads(value.parse()?);
+        }
+        if let Ok(value) = env::var("FLYTILE_ELEVATION_MAX_CELLS") {
+            elevation = elevation.with_max_cells(value.parse()?);
+        }
+        Ok(elevation)
+    }
+
+    pub fn with_void_fill(mut self, void_fill: VoidFill) -> Self {
+        self.void_fill = void_fill;
+        self
+    }
+
+    /// limit the number of cells requested from the source at once
+    pub fn with_parallel_downloads(mut self, parallel: usize) -> Self {
+        self.download_limit = tokio::sync::Semaphore::new(parallel.max(1));
+        self
+    }
+
+    /// limit the number of cells a single call to `get_all` may use
+    pub fn with_max_cells(mut self, max_cells: usize) -> Self {
+        self.max_cells = max_cells;
+        self
+    }
+
+    pub fn source(&self) -> &dyn ElevationSource {
+        self.source.as_ref()
+    }
+
+    pub async fn get(&self, point: tile::GeoPoint) -> Result<Cell> {
+        let id = self.source.cell_id(&point);
+        log::debug!("get {} {} for point {:?}", self.source.name(), id, point);
+        self.get_cell(&id).await
+    }
+
+    /// Get a cell, substituting a sea level cell if it does not exist
+    pub async fn get_cell(&self, id: &str) -> Result<Cell> {
+        if self.index.lock().unwrap().is_absent(id) {
+            log::debug!("cell {} is known to be missing", id);
+            return self.get_sea_level(id).await;
+        }
+        let out_dir = self.cache_dir.clone();
+        let source = self.source.clone();
+        let tmp: String = id.to_string();
+        let generator = move || source.fetch_cell(&out_dir, &tmp);
+        let permit = self.download_limit.acquire().await?;
+        let result = self.request(id.into(), Box::new(generator)).await;
+        drop(permit);
+        let path = match result {
+            Ok(path) => {
+                self.update_index(|index| index.mark_present(id));
+                path
+            }
+            Err(error) => match error.downcast_ref::<cache::GeneratorError>() {
+                Some(inner) if inner.kind() == &cache::ErrorKind::NotFound => {
+                    log::info!("cell {} does not exist, using sea level", id);
+                    self.update_index(|index| index.mark_absent(id));
+                    return self.get_sea_level(id).await;
+                }
+                _ => return Err(error),
+            },
+        };
+        let void_fraction = self.void_fraction(id, &path)?;
+        let mut cell = Cell {
+            id: id.to_string(),
+            path,
+            void_fraction,
+            interpolated: false,
+        };
+        if void_fraction > 0.0 {
+            self.fill_voids(&mut cell).await?;
+        }
+        Ok(cell)
+    }
+
+    fn void_fraction(&self, id: &str, path: &Path) -> Result<f64> {
+        if let Some(fraction) = self.index.lock().unwrap().void_fraction(id) {
+            return Ok(fraction);
+        }
+        // voids can only be counted in raw height files
+        if path.extension().is_none_or(|extension| extension != "hgt") {
+            return Ok(0.0);
+        }
+        let (voids, total) = srtm::count_voids(path)?;
+        let fraction = if total > 0 {
+            voids as f64 / total as f64
+        } else {
+            0.0
+        };
+        log::info!("cell {} has {} of {} samples void", id, voids, total);
+        self.update_index(|index| index.set_void_fraction(id, fraction));
+        Ok(fraction)
+    }
+
+    async fn fill_voids(&self, cell: &mut Cell) -> Result<()> {
+        let key = PathBuf::new()
+            .join("filled")
+            .join(format!("{}.tif", cell.id));
+        let output = self.cache_dir.join(&key);
+        let input = cell.path.clone();
+        let void_value = self.source.void_value();
+        let generator: Box<dyn FnOnce() -> cache::CacheResult + Send> = match &self.void_fill {
+            VoidFill::None => return Ok(()),
+            VoidFill::Interpolate { max_distance } => {
+                let max_distance = *max_distance;
+                Box::new(move || interpolate_voids(input, output, max_distance))
+            }
+            VoidFill::Secondary(secondary) => {
+                let bounds = self
+                    .source
+                    .cell_bounds(&cell.id)
+                    .ok_or(anyhow!("can not get bounds of cell {}", cell.id))?;
+                let center = tile::GeoPoint {
+                    longitude: (bounds.min_longitude + bounds.max_longitude) / 2.0,
+                    latitude: (bounds.min_latitude + bounds.max_latitude) / 2.0,
+                };
+                let fill = Box::pin(secondary.get(center)).await?.path;
+                let voids = (void_value, secondary.source.void_value());
+                Box::new(move || fill_from_secondary(input, fill, output, voids, bounds))
+            }
+        };
+        log::debug!("filling voids in cell {}", cell.id);
+        cell.path = self.request(key, generator).await?;
+        cell.interpolated = true;
+        Ok(())
+    }
+
+    async fn get_sea_level(&self, id: &str) -> Result<Cell> {
+        let bounds = self
+            .source
+            .cell_bounds(id)
+            .ok_or(anyhow!("can not get bounds of cell {}", id))?;
+        let key = PathBuf::new().join("sea_level").join(format!("{}.vrt", id));
+        let output = self.cache_dir.join(&key);
+        let generator = move || sea_level(output, bounds);
+        Ok(Cell {
+            id: id.to_string(),
+            path: self.request(key, Box::new(generator)).await?,
+            void_fraction: 0.0,
+            interpolated: false,
+        })
+    }
+
+    async fn request(
+        &self,
+        key: PathBuf,
+        generator: Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    ) -> Result<PathBuf> {
+        cache::ask(&self.cache_tx, key, generator).await
+    }
+
+    fn update_index<F>(&self, update: F)
+    where
+        F: FnOnce(&mut CellIndex) -> bool,
+    {
+        let mut index = self.index.lock().unwrap();
+        if update(&mut index) {
+            if let Err(error) = index.save() {
+                log::warn!("could not save cell index: {}", error);
+            }
+        }
+    }
+
+    pub async fn get_all(&self, bounds: tile::Bounds) -> Result<Vec<Cell>> {
+        let min_lon = bounds
+            .north_west
+            .longitude
+            .min(bounds.south_west.longitude)
+            .floor() as i32;
+        let min_lat = bounds
+            .south_west
+            .latitude
+            .min(bounds.south_east.latitude)
+            .floor() as i32;
+        let max_lon = bounds
+            .north_east
+            .longitude
+            .max(bounds.south_east.longitude)
+            .ceil() as i32;
+        let max_lat = bounds
+            .north_west
+            .latitude
+            .max(bounds.north_east.latitude)
+            .ceil() as i32;
+        log::debug!("lon bounds {} {}", min_lon, max_lon);
+        log::debug!("lat bounds {} {}", min_lat, max_lat);
+        let count = ((max_lon - min_lon) * (max_lat - min_lat)) as usize;
+        if count > self.max_cells {
+            return Err(anyhow!(
+                "area needs {} elevation cells, more than the limit of {}",
+                count,
+                self.max_cells
+            ));
+        }
+        let mut requests = vec![];
+        for i in min_lon..max_lon {
+            for j in min_lat..max_lat {
+                let point = tile::GeoPoint {
+                    longitude: i as f64,
+                    latitude: j as f64,
+                };
+                requests.push(self.get(point));
+            }
+        }
+        return future::try_join_all(requests).await;
+    }
+}
+
+/// Write a raster of zero elevation covering a cell
+///
+/// This is a VRT without sources so it takes no space however large it is.
+fn sea_level(output: PathBuf, bounds: CellBounds) -> cache::CacheResult {
+    let parent = output.parent().expect("output should have parent dir");
+    if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    // match the one arc second pixel-centered grid of SRTM cells
+    let size = 3601;
+    let step = (bounds.max_longitude - bounds.min_longitude) / (size - 1) as f64;
+    let vrt = format!(
+        r#"<VRTDataset rasterXSize="{size}" rasterYSize="{size}">
+  <SRS>EPSG:4326</SRS>
+  <GeoTransform>{west}, {step}, 0, {north}, 0, {negative_step}</GeoTransform>
+  <VRTRasterBand dataType="Int16" band="1" />
+</VRTDataset>
+"#,
+        west = bounds.min_longitude - step / 2.0,
+        north = bounds.max_latitude + step / 2.0,
+        negative_step = -step,
+    );
+    fs::write(&output, vrt)?;
+    Ok(output)
+}
+
+fn interpolate_voids(input: PathBuf, output: PathBuf, max_distance: u32) -> cache::CacheResult {
+    let parent = output.parent().expect("output should have parent dir");
+    if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    log::info!("interpolating voids in {:?}", input);
+    let result = process::Command::new("gdal_fillnodata.py")
+        .arg("-md")
+        .arg(format!("{}", max_distance))
+        .arg("-of")
+        .arg("GTiff")
+        .arg(&input)
+        .arg(&output)
+        .output()?;
+    if !result.status.success() {
+        return Err(cache::GeneratorError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(output)
+}
+
+/// Mosaic a cell on top of a secondary source so its voids show the secondary
+///
+/// `void_values` are the no data values of the cell and of the secondary,
+/// which differ between sources.
+fn fill_from_secondary(
+    input: PathBuf,
+    secondary: PathBuf,
+    output: PathBuf,
+    (void_value, secondary_void_value): (f64, f64),
+    bounds: CellBounds,
+) -> cache::CacheResult {
+    let parent = output.parent().expect("output should have parent dir");
+    if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    log::info!("filling voids in {:?} from {:?}", input, secondary);
+    // tag each input with its own no data value, which gdalwarp then honours
+    let dir = tempfile::tempdir()?;
+    let input = with_no_data(&input, void_value, &dir.path().join("input.vrt"))?;
+    let secondary = with_no_data(
+        &secondary,
+        secondary_void_value,
+        &dir.path().join("secondary.vrt"),
+    )?;
+    let step = 1.0 / 3600.0;
+    let result = process::Command::new("gdalwarp")
+        .arg("-overwrite")
+        .arg("-dstnodata")
+        .arg(format!("{}", void_value))
+        .arg("-te")
+        .arg(format!("{}", bounds.min_longitude - step / 2.0))
+        .arg(format!("{}", bounds.min_latitude - step / 2.0))
+        .arg(format!("{}", bounds.max_longitude + step / 2.0))
+        .arg(format!("{}", bounds.max_latitude + step / 2.0))
+        .arg("-tr")
+        .arg(format!("{}", step))
+        .arg(format!("{}", step))
+        .arg("-r")
+        .arg("bilinear")
+        .arg("-ot")
+        .arg("Float32")
+        .arg(&secondary)
+        .arg(&input)
+        .arg(&output)
+        .output()?;
+    if !result.status.success() {
+        return Err(cache::GeneratorError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(output)
+}
+
+/// Describe a raster as a VRT with the given no data value
+fn with_no_data(input: &Path, void_value: f64, output: &Path) -> cache::CacheResult {
+    let result = process::Command::new("gdal_translate")
+        .arg("-of")
+        .arg("VRT")
+        .arg("-a_nodata")
+        .arg(format!("{}", void_value))
+        .arg(input)
+        .arg(output)
+        .output()?;
+    if !result.status.success() {
+        return Err(cache::GeneratorError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(output.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_cell_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = index_path(&dir.path().join("srtm"));
+        assert!(path.ends_with("srtm.cells.json"));
+        let mut index = CellIndex::load(path.clone());
+        assert!(!index.is_absent("N49W120.hgt"));
+        assert!(index.mark_absent("N49W130.hgt"));
+        assert!(!index.mark_absent("N49W130.hgt"));
+        assert!(index.mark_present("N49W120.hgt"));
+        index.save().unwrap();
+
+        let mut index = CellIndex::load(path.clone());
+        assert!(index.is_absent("N49W130.hgt"));
+        assert!(!index.is_absent("N49W120.hgt"));
+        assert!(!index.is_absent("N50W120.hgt"));
+        index.complete = true;
+        assert!(index.is_absent("N50W120.hgt"));
+        assert!(!index.is_absent("N49W120.hgt"));
+
+        assert!(index.void_fraction("N49W120.hgt").is_none());
+        assert!(index.set_void_fraction("N49W120.hgt", 0.25));
+        assert!(!index.set_void_fraction("N49W120.hgt", 0.25));
+        assert_eq!(index.void_fraction("N49W120.hgt"), Some(0.25));
+    }
+
+    #[test]
+    fn test_cell_index_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = index_path(&dir.path().join("srtm"));
+        let mut server = CellIndex::load(path.clone());
+        let mut importer = CellIndex::load(path.clone());
+        importer.mark_present("N49W120.hgt");
+        importer.set_void_fraction("N49W120.hgt", 0.5);
+        importer.mark_present("N49W121.hgt");
+        importer.save().unwrap();
+        server.mark_absent("N49W130.hgt");
+        server.mark_absent("N49W121.hgt");
+        server.save().unwrap();
+
+        let index = CellIndex::load(path);
+        assert!(!index.is_absent("N49W120.hgt"));
+        assert!(!index.is_absent("N49W121.hgt"));
+        assert!(index.is_absent("N49W130.hgt"));
+        assert_eq!(index.void_fraction("N49W120.hgt"), Some(0.5));
+    }
+
+    /// Source which takes a while to fetch and records how many fetch at once
+    struct SlowSource {
+        active: Mutex<(usize, usize)>,
+    }
+
+    impl ElevationSource for SlowSource {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn cell_id(&self, point: &tile::GeoPoint) -> String {
+            srtm::srtm_id(point)
+        }
+
+        fn cell_bounds(&self, id: &str) -> Option<CellBounds> {
+            srtm::srtm_bounds(id)
+        }
+
+        fn void_value(&self) -> f64 {
+            srtm::VOID_VALUE
+        }
+
+        fn fetch_cell(&self, output_directory: &Path, id: &str) -> cache::CacheResult {
+            {
+                let mut active = self.active.lock().unwrap();
+                active.0 += 1;
+                active.1 = active.1.max(active.0);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            self.active.lock().unwrap().0 -= 1;
+            let output = output_directory.join(id);
+            fs::write(&output, "cell")?;
+            Ok(output)
+        }
+    }
+
+    fn tile_bounds(west: f64, south: f64, east: f64, north: f64) -> tile::Bounds {
+        tile::Bounds {
+            north_west: tile::GeoPoint {
+                longitude: west,
+                latitude: north,
+            },
+            north_east: tile::GeoPoint {
+                longitude: east,
+                latitude: north,
+            },
+            south_west: tile::GeoPoint {
+                longitude: west,
+                latitude: south,
+            },
+            south_east: tile::GeoPoint {
+                longitude: east,
+                latitude: south,
+            },
+        }
+    }
+
+    #[test]
+    fn test_get_all_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = Arc::new(SlowSource {
+            active: Mutex::new((0, 0)),
+        });
+        let elevation = Elevation::new(dir.path().join("slow"), source.clone())
+            .with_parallel_downloads(3)
+            .with_max_cells(16);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        let cells = runtime
+            .block_on(elevation.get_all(tile_bounds(-121.5, 48.5, -118.5, 49.5)))
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(cells.len(), 8);
+        assert_eq!(cells[0].id, "N48W122.hgt");
+        assert_eq!(cells[7].id, "N49W119.hgt");
+        // eight cells three at a time takes three rounds
+        assert!(
+            elapsed < std::time::Duration::from_millis(1500),
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(source.active.lock().unwrap().1, 3);
+
+        let too_many = runtime.block_on(elevation.get_all(tile_bounds(-125.0, 40.0, -120.0, 45.0)));
+        assert!(too_many.is_err());
+    }
+
+    #[test]
+    fn test_sea_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sea_level").join("N49W130.hgt.vrt");
+        let bounds = srtm::srtm_bounds("N49W130.hgt").unwrap();
+        let result = sea_level(output.clone(), bounds).unwrap();
+        assert_eq!(result, output);
+        let text = fs::read_to_string(&output).unwrap();
+        assert!(text.contains(r#"rasterXSize="3601""#));
+        assert!(text.contains("<GeoTransform>-130.000138"));
+    }
+}
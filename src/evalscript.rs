@@ -0,0 +1,296 @@
+use crate::process_api::Resampling;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Script used for imagery when none is named
+pub const DEFAULT: &str = "true-color";
+
+/// Collection read by scripts without a `//COLLECTION=` line
+pub const DEFAULT_COLLECTION: &str = "sentinel-2-l1c";
+
+const BUILTIN: [(&str, &str); 9] = [
+    ("true-color", include_str!("evalscripts/true-color.js")),
+    (
+        "true-color-l2a",
+        include_str!("evalscripts/true-color-l2a.js"),
+    ),
+    ("false-color", include_str!("evalscripts/false-color.js")),
+    ("ndvi", include_str!("evalscripts/ndvi.js")),
+    ("ndsi", include_str!("evalscripts/ndsi.js")),
+    ("ndsi-index", include_str!("evalscripts/ndsi-index.js")),
+    ("swir", include_str!("evalscripts/swir.js")),
+    ("moisture", include_str!("evalscripts/moisture.js")),
+    ("snow", include_str!("evalscripts/snow.js")),
+];
+
+/// Sentinel-1 backscatter scripts, served apart from the optical imagery
+const SAR: [(&str, &str); 3] = [
+    ("vv", include_str!("evalscripts/sar-vv.js")),
+    ("vh", include_str!("evalscripts/sar-vh.js")),
+    ("vv-vh", include_str!("evalscripts/sar-vv-vh.js")),
+];
+
+/// Landsat 8/9 scripts named like the optical scripts they stand in for
+const LANDSAT: [(&str, &str); 6] = [
+    (
+        "true-color",
+        include_str!("evalscripts/landsat-true-color.js"),
+    ),
+    (
+        "false-color",
+        include_str!("evalscripts/landsat-false-color.js"),
+    ),
+    ("ndvi", include_str!("evalscripts/landsat-ndvi.js")),
+    ("ndsi", include_str!("evalscripts/landsat-ndsi.js")),
+    ("swir", include_str!("evalscripts/landsat-swir.js")),
+    ("moisture", include_str!("evalscripts/landsat-moisture.js")),
+];
+
+/// A Sentinel Hub evalscript rendering imagery tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evalscript {
+    pub name: String,
+    pub source: String,
+    /// number of input bands, which sets the processing units charged
+    pub bands: u32,
+    /// data collection the script reads, like `sentinel-2-l2a`
+    pub collection: String,
+    /// interpolation of the layer when tiles are finer than the data, unless
+    /// a request asks for another
+    pub upsampling: Option<Resampling>,
+    /// interpolation of the layer when tiles are coarser than the data
+    pub downsampling: Option<Resampling>,
+}
+
+impl Evalscript {
+    /// A script may name its collection on a line like `//COLLECTION=sentinel-2-l2a`
+    /// and its resampling on lines like `//UPSAMPLING=BICUBIC` and
+    /// `//DOWNSAMPLING=BILINEAR`
+    pub fn new(name: &str, source: &str) -> Self {
+        let collection = setting(source, "COLLECTION").unwrap_or(DEFAULT_COLLECTION);
+        let resampling = |key| {
+            let value = setting(source, key)?;
+            let method = Resampling::parse(value);
+            if method.is_none() {
+                log::warn!("ignoring {} {} of evalscript {}", key, value, name);
+            }
+            method
+        };
+        Evalscript {
+            name: name.to_string(),
+            source: source.to_string(),
+            bands: count_input_bands(source),
+            collection: collection.trim().to_string(),
+            upsampling: resampling("UPSAMPLING"),
+            downsampling: resampling("DOWNSAMPLING"),
+        }
+    }
+}
+
+/// Value of a `//<key>=<value>` line of a script
+fn setting<'a>(source: &'a str, key: &str) -> Option<&'a str> {
+    source.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("//")
+            .and_then(|line| line.strip_prefix(key))
+            .and_then(|line| line.strip_prefix('='))
+    })
+}
+
+/// Count the bands listed in the `input` of a script's `setup`, the data mask
+/// is free and anything unparseable counts as three bands
+fn count_input_bands(source: &str) -> u32 {
+    let bands = source
+        .find("input:")
+        .map(|start| &source[start..])
+        .and_then(|input| input.find(']').map(|end| &input[..end]))
+        .map(|input| {
+            input
+                .split('"')
+                .skip(1)
+                .step_by(2)
+                .filter(|band| *band != "dataMask")
+                .count() as u32
+        })
+        .unwrap_or(0);
+    if bands == 0 {
+        3
+    } else {
+        bands
+    }
+}
+
+/// Named evalscripts, each served as its own imagery layer
+#[derive(Debug, Clone)]
+pub struct Library {
+    scripts: BTreeMap<String, Evalscript>,
+}
+
+impl Library {
+    /// True and false colour, cloud masked L2A true colour, NDVI, NDSI snow,
+    /// SWIR, moisture index, a snow classification and the NDSI read by the
+    /// change layer
+    pub fn builtin() -> Self {
+        Library::from_sources(&BUILTIN)
+    }
+
+    /// VV and VH backscatter in decibels and a VV, VH false colour composite
+    pub fn sar() -> Self {
+        Library::from_sources(&SAR)
+    }
+
+    /// True and false colour, NDVI, NDSI snow, SWIR and moisture index from
+    /// Landsat 8/9 surface reflectance
+    pub fn landsat() -> Self {
+        Library::from_sources(&LANDSAT)
+    }
+
+    fn from_sources(sources: &[(&str, &str)]) -> Self {
+        let scripts = sources
+            .iter()
+            .map(|(name, source)| (name.to_string(), Evalscript::new(name, source)))
+            .collect();
+        Library { scripts }
+    }
+
+    /// Add every `<name>.js` file in a directory, replacing built in scripts
+    /// of the same name
+    pub fn load_directory(&mut self, directory: &Path) -> Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "js") {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                log::warn!("skipping evalscript {:?}, names use a-z, 0-9 and -", path);
+                continue;
+            }
+            log::info!("loaded evalscript {} from {:?}", name, path);
+            let script = Evalscript::new(&name, &fs::read_to_string(&path)?);
+            self.scripts.insert(name, script);
+        }
+        Ok(())
+    }
+
+    /// Built in scripts plus any in `FLYTILE_EVALSCRIPT_DIR`
+    pub fn from_env() -> Result<Self> {
+        let mut library = Library::builtin();
+        if let Ok(directory) = env::var("FLYTILE_EVALSCRIPT_DIR") {
+            library.load_directory(Path::new(&directory))?;
+        }
+        Ok(library)
+    }
+
+    /// Look up a script, `latest` is kept as a name for true colour
+    pub fn get(&self, name: &str) -> Option<&Evalscript> {
+        let name = if name == "latest" { DEFAULT } else { name };
+        self.scripts.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.scripts.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let library = Library::builtin();
+        assert_eq!(
+            library.names(),
+            vec![
+                "false-color",
+                "moisture",
+                "ndsi",
+                "ndsi-index",
+                "ndvi",
+                "snow",
+                "swir",
+                "true-color",
+                "true-color-l2a"
+            ]
+        );
+        assert_eq!(library.get("latest").unwrap().name, "true-color");
+        assert_eq!(library.get("ndsi").unwrap().bands, 4);
+        assert_eq!(library.get("ndvi").unwrap().bands, 2);
+        assert_eq!(library.get("ndvi").unwrap().collection, "sentinel-2-l1c");
+        assert_eq!(library.get("snow").unwrap().collection, "sentinel-2-l2a");
+        let l2a = library.get("true-color-l2a").unwrap();
+        assert_eq!(l2a.collection, "sentinel-2-l2a");
+        assert_eq!(l2a.bands, 4);
+        assert!(library.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_sar() {
+        let library = Library::sar();
+        assert_eq!(library.names(), vec!["vh", "vv", "vv-vh"]);
+        assert_eq!(library.get("vv").unwrap().collection, "sentinel-1-grd");
+        assert_eq!(library.get("vv").unwrap().bands, 1);
+        assert_eq!(library.get("vv-vh").unwrap().bands, 2);
+        assert!(library.get("true-color").is_none());
+    }
+
+    #[test]
+    fn test_landsat() {
+        let library = Library::landsat();
+        let builtin = Library::builtin();
+        for name in library.names() {
+            assert!(builtin.get(name).is_some());
+            assert_eq!(library.get(name).unwrap().collection, "landsat-ot-l2");
+        }
+        assert_eq!(library.get("latest").unwrap().bands, 3);
+        assert_eq!(library.get("ndsi").unwrap().bands, 4);
+        assert!(library.get("snow").is_none());
+    }
+
+    #[test]
+    fn test_count_input_bands() {
+        assert_eq!(
+            count_input_bands(r#"input: ["B03", "B11", "dataMask"],"#),
+            2
+        );
+        assert_eq!(count_input_bands(r#"input: [{bands: ["B04", "B08"]}],"#), 2);
+        assert_eq!(count_input_bands("function setup() {}"), 3);
+    }
+
+    #[test]
+    fn test_settings() {
+        let script = Evalscript::new(
+            "smooth",
+            "//VERSION=3\n//COLLECTION=sentinel-2-l2a\n//UPSAMPLING=BICUBIC\n//DOWNSAMPLING=fuzzy\n",
+        );
+        assert_eq!(script.collection, "sentinel-2-l2a");
+        assert_eq!(script.upsampling, Some(Resampling::Bicubic));
+        assert_eq!(script.downsampling, None);
+        assert_eq!(Library::builtin().get("ndvi").unwrap().upsampling, None);
+    }
+
+    #[test]
+    fn test_load_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("ndsi.js"), r#"input: ["B03", "B11"]"#).unwrap();
+        fs::write(dir.path().join("burn-scar.js"), r#"input: ["B08", "B12"]"#).unwrap();
+        fs::write(dir.path().join("Bad Name.js"), "").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        let mut library = Library::builtin();
+        library.load_directory(dir.path()).unwrap();
+        assert_eq!(library.names().len(), 10);
+        assert_eq!(library.get("ndsi").unwrap().bands, 2);
+        assert!(library.get("burn-scar").is_some());
+    }
+}
@@ -0,0 +1,18 @@
+//VERSION=3
+// vegetation shows red in near infrared, green and red bands
+
+function setup() {
+  return {
+    input: ["B03", "B04", "B08"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  return [ 2.5 * samples[0].B08, 2.5 * samples[0].B04, 2.5 * samples[0].B03 ]
+}
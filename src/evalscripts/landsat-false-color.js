@@ -0,0 +1,19 @@
+//VERSION=3
+//COLLECTION=landsat-ot-l2
+// vegetation shows red in near infrared, green and red bands of Landsat 8/9
+
+function setup() {
+  return {
+    input: ["B03", "B04", "B05"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  return [ 2.5 * samples[0].B05, 2.5 * samples[0].B04, 2.5 * samples[0].B03 ]
+}
@@ -0,0 +1,24 @@
+//VERSION=3
+//COLLECTION=landsat-ot-l2
+// normalized difference snow index of Landsat 8/9, snow above 0.4 shows in blue over true colour
+
+function setup() {
+  return {
+    input: ["B02", "B03", "B04", "B06"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  let ndsi = (s.B03 - s.B06) / (s.B03 + s.B06);
+  if (ndsi > 0.4) {
+    return [0.0, 0.6, 1.0];
+  }
+  return [ 2.5 * s.B04, 2.5 * s.B03, 2.5 * s.B02 ];
+}
@@ -0,0 +1,19 @@
+//VERSION=3
+//COLLECTION=landsat-ot-l2
+// short wave infrared composite of Landsat 8/9, snow and ice show cyan and clouds white
+
+function setup() {
+  return {
+    input: ["B04", "B05", "B07"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  return [ 2.5 * samples[0].B07, 2.5 * samples[0].B05, 2.5 * samples[0].B04 ]
+}
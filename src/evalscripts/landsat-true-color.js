@@ -0,0 +1,19 @@
+//VERSION=3
+//COLLECTION=landsat-ot-l2
+// natural colour from Landsat 8/9 surface reflectance
+
+function setup() {
+  return {
+    input: ["B02", "B03", "B04"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  return [ 2.5 * samples[0].B04, 2.5 * samples[0].B03, 2.5 * samples[0].B02 ]
+}
@@ -0,0 +1,28 @@
+//VERSION=3
+// normalized difference moisture index from dry (red) to wet (blue)
+
+function setup() {
+  return {
+    input: ["B8A", "B11"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+const ramp = [
+  [-0.8, [0.5, 0.0, 0.0]],
+  [-0.24, [1.0, 0.4, 0.0]],
+  [0.0, [1.0, 1.0, 0.6]],
+  [0.24, [0.4, 0.8, 1.0]],
+  [0.8, [0.0, 0.0, 0.6]]
+];
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  let moisture = (s.B8A - s.B11) / (s.B8A + s.B11);
+  return colorBlend(moisture, ramp.map(r => r[0]), ramp.map(r => r[1]));
+}
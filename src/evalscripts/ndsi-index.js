@@ -0,0 +1,32 @@
+//VERSION=3
+//COLLECTION=sentinel-2-l2a
+// NDSI from -1 to 1 as grey from 1 to 255 for the change layer to read back,
+// pixels which are cloud or cloud shadow in every scene are transparent
+
+function setup() {
+  return {
+    input: ["B03", "B11", "SCL", "dataMask"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 4, sampleType: "UINT8" }
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+// scene classification values
+const NO_DATA = [0, 1];
+const CLOUD = [3, 8, 9, 10];
+
+function evaluatePixel(samples) {
+  for (let s of samples) {
+    if (s.dataMask == 0 || NO_DATA.includes(s.SCL) || CLOUD.includes(s.SCL)) {
+      continue;
+    }
+    let ndsi = (s.B03 - s.B11) / (s.B03 + s.B11);
+    let grey = Math.max(1, Math.min(255, Math.round(1 + 127 * (ndsi + 1))));
+    return [grey, grey, grey, 255];
+  }
+  return [0, 0, 0, 0];
+}
@@ -0,0 +1,29 @@
+//VERSION=3
+// normalized difference vegetation index from brown (bare) to green (dense)
+
+function setup() {
+  return {
+    input: ["B04", "B08"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+const ramp = [
+  [-0.2, [0.05, 0.05, 0.05]],
+  [0.0, [0.75, 0.65, 0.5]],
+  [0.2, [0.8, 0.85, 0.4]],
+  [0.4, [0.4, 0.7, 0.2]],
+  [0.6, [0.15, 0.5, 0.1]],
+  [0.8, [0.0, 0.3, 0.0]]
+];
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  let ndvi = (s.B08 - s.B04) / (s.B08 + s.B04);
+  return colorBlend(ndvi, ramp.map(r => r[0]), ramp.map(r => r[1]));
+}
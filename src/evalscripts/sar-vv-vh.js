@@ -0,0 +1,29 @@
+//VERSION=3
+//COLLECTION=sentinel-1-grd
+// false colour backscatter, VV in red, VH in green and the VV/VH ratio in blue
+
+function setup() {
+  return {
+    input: ["VV", "VH", "dataMask"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 4, sampleType: "UINT8" }
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function scale(value, min, max) {
+  return Math.max(0, Math.min(255, Math.round(255 * (value - min) / (max - min))));
+}
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  if (s.dataMask == 0 || s.VV <= 0 || s.VH <= 0) {
+    return [0, 0, 0, 0];
+  }
+  let vv = 10 * Math.log(s.VV) / Math.LN10;
+  let vh = 10 * Math.log(s.VH) / Math.LN10;
+  return [scale(vv, -25, 5), scale(vh, -30, -5), scale(vv - vh, 0, 15), 255];
+}
@@ -0,0 +1,30 @@
+//VERSION=3
+//COLLECTION=sentinel-1-grd
+// VV backscatter in decibels as grey from -25 dB (black) to 5 dB (white), the
+// SAR ratio layer reads these values back so keep the scale in step with sar.rs
+
+function setup() {
+  return {
+    input: ["VV", "dataMask"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 4, sampleType: "UINT8" }
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+const MIN_DB = -25;
+const MAX_DB = 5;
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  if (s.dataMask == 0 || s.VV <= 0) {
+    return [0, 0, 0, 0];
+  }
+  let db = 10 * Math.log(s.VV) / Math.LN10;
+  let grey = Math.round(1 + 254 * (db - MIN_DB) / (MAX_DB - MIN_DB));
+  grey = Math.max(1, Math.min(255, grey));
+  return [grey, grey, grey, 255];
+}
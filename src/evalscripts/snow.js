@@ -0,0 +1,36 @@
+//VERSION=3
+//COLLECTION=sentinel-2-l2a
+// snow classification overlay, the colours are read back by the steep snow layer:
+// snow is blue, cloud grey, no data dark and ground without snow clear
+
+function setup() {
+  return {
+    input: ["B03", "B11", "SCL", "dataMask"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 4, sampleType: "UINT8" }
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+// scene classification values
+const NO_DATA = [0, 1];
+const CLOUD = [3, 8, 9, 10];
+const SNOW = 11;
+
+function evaluatePixel(samples) {
+  let s = samples[0];
+  if (s.dataMask == 0 || NO_DATA.includes(s.SCL)) {
+    return [0, 0, 0, 96];
+  }
+  if (CLOUD.includes(s.SCL)) {
+    return [128, 128, 128, 128];
+  }
+  let ndsi = (s.B03 - s.B11) / (s.B03 + s.B11);
+  if (s.SCL == SNOW || (ndsi > 0.4 && s.B03 > 0.1)) {
+    return [0, 153, 255, 204];
+  }
+  return [0, 0, 0, 0];
+}
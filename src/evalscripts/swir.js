@@ -0,0 +1,18 @@
+//VERSION=3
+// short wave infrared composite, snow and ice show cyan and clouds white
+
+function setup() {
+  return {
+    input: ["B04", "B8A", "B12"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 3}
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+function evaluatePixel(samples) {
+  return [ 2.5 * samples[0].B12, 2.5 * samples[0].B8A, 2.5 * samples[0].B04 ]
+}
@@ -0,0 +1,31 @@
+//VERSION=3
+//COLLECTION=sentinel-2-l2a
+// true colour from atmospherically corrected scenes, pixels which are cloud or
+// cloud shadow in every scene are transparent
+
+function setup() {
+  return {
+    input: ["B02", "B03", "B04", "SCL", "dataMask"],
+    mosaicking: Mosaicking.ORBIT,
+    output: { id:"default", bands: 4, sampleType: "UINT8" }
+  }
+}
+
+function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
+    outputMetadata.userData = { "scenes":  scenes.orbits }
+}
+
+// scene classification values
+const NO_DATA = [0, 1];
+const CLOUD = [3, 8, 9, 10];
+
+function evaluatePixel(samples) {
+  // scenes come in the requested mosaicking order, use the first clear one
+  for (let s of samples) {
+    if (s.dataMask == 0 || NO_DATA.includes(s.SCL) || CLOUD.includes(s.SCL)) {
+      continue;
+    }
+    return [255 * 2.5 * s.B04, 255 * 2.5 * s.B03, 255 * 2.5 * s.B02, 255];
+  }
+  return [0, 0, 0, 0];
+}
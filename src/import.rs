@@ -0,0 +1,394 @@
+use crate::cache;
+use crate::elevation;
+use crate::processing::{ProcessingError, ProcessingResult};
+use crate::regional;
+use crate::srtm;
+use anyhow::Result;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::result::Result as StdResult;
+use tempfile::NamedTempFile;
+
+/// Outcome of importing elevation files into a cache directory
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// ids of cells written to the cache
+    pub imported: Vec<String>,
+    /// ids of cells already in the cache, which are left alone
+    pub skipped: Vec<String>,
+    /// files which could not be imported and why
+    pub rejected: Vec<(PathBuf, String)>,
+}
+
+/// Import `.hgt`, `.hgt.zip` and GeoTIFF elevation files into an SRTM cache
+///
+/// The input may be a single file, a directory which is searched recursively
+/// or a `.tar`/`.tar.gz` archive. Cells are named by `srtm::srtm_id`, checked
+/// the same way as downloads, adopted by `cache` and recorded as present in
+/// the cell index so the server never requests them.
+pub fn import(input: &Path, cache: &mut cache::Cache) -> Result<ImportReport> {
+    let cache_dir = cache.cache.clone();
+    let cache_dir = cache_dir.as_path();
+    let unpacked = tempfile::tempdir()?;
+    let root = if is_tarball(input) {
+        log::info!("unpacking {:?}", input);
+        unpack(input, unpacked.path())?;
+        unpacked.path().to_path_buf()
+    } else {
+        input.to_path_buf()
+    };
+    let mut files = Vec::new();
+    find_files(&root, &mut files)?;
+    files.sort();
+
+    let mut report = ImportReport::default();
+    let mut index = elevation::CellIndex::load(elevation::index_path(cache_dir));
+    for file in files {
+        let result = match file_type(&file) {
+            Some(FileType::Height) => import_height(&file, cache_dir),
+            Some(FileType::Zip) => import_zip(&file, cache_dir),
+            Some(FileType::GeoTiff) => import_geotiff(&file, cache_dir),
+            None => {
+                log::debug!("ignoring {:?}", file);
+                continue;
+            }
+        };
+        match result {
+            Ok(cells) => {
+                for cell in cells {
+                    match cell {
+                        Imported::New(id, path) => {
+                            let (voids, total) = match srtm::count_voids(&path) {
+                                Ok(counts) => counts,
+                                Err(error) => {
+                                    log::warn!("could not import {:?}: {}", file, error);
+                                    let _ = fs::remove_file(&path);
+                                    report.rejected.push((file.clone(), error.to_string()));
+                                    continue;
+                                }
+                            };
+                            cache.adopt(PathBuf::from(&id))?;
+                            index.mark_present(&id);
+                            index.set_void_fraction(&id, voids as f64 / total as f64);
+                            log::info!("imported {} from {:?}", id, file);
+                            report.imported.push(id);
+                        }
+                        Imported::Existing(id) => {
+                            index.mark_present(&id);
+                            report.skipped.push(id);
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                log::warn!("could not import {:?}: {}", file, error);
+                report.rejected.push((file, error.to_string()));
+            }
+        }
+    }
+    index.save()?;
+    Ok(report)
+}
+
+enum FileType {
+    Height,
+    Zip,
+    GeoTiff,
+}
+
+enum Imported {
+    New(String, PathBuf),
+    Existing(String),
+}
+
+fn file_type(path: &Path) -> Option<FileType> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.starts_with('.') {
+        None
+    } else if name.ends_with(".hgt") {
+        Some(FileType::Height)
+    } else if name.ends_with(".zip") {
+        Some(FileType::Zip)
+    } else if name.ends_with(".tif") || name.ends_with(".tiff") {
+        Some(FileType::GeoTiff)
+    } else {
+        None
+    }
+}
+
+fn is_tarball(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+    name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+fn unpack(path: &Path, output: &Path) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let name = path.to_string_lossy().to_lowercase();
+    if name.ends_with(".tar") {
+        tar::Archive::new(file).unpack(output)
+    } else {
+        tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(output)
+    }
+}
+
+fn find_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            find_files(&entry?.path(), files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Name a cell from a file name like `n49w120.SRTMGL1.hgt.zip`
+fn cell_id(name: &str) -> Option<String> {
+    let stem = name.split('.').next()?.to_uppercase();
+    srtm::srtm_bounds(&stem)?;
+    Some(format!("{}.hgt", stem))
+}
+
+fn file_cell_id(path: &Path) -> StdResult<String, cache::GeneratorError> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    cell_id(&name).ok_or(cache::GeneratorError::new(&format!(
+        "{} is not named like an srtm cell",
+        name
+    )))
+}
+
+fn import_height(path: &Path, cache_dir: &Path) -> StdResult<Vec<Imported>, cache::GeneratorError> {
+    let id = file_cell_id(path)?;
+    let output = cache_dir.join(&id);
+    if output.exists() {
+        return Ok(vec![Imported::Existing(id)]);
+    }
+    let bytes = fs::metadata(path)?.len();
+    if !srtm::HGT_SIZES.contains(&bytes) {
+        return Err(cache::GeneratorError::new(&format!(
+            "{} has unexpected size {}",
+            id, bytes
+        )));
+    }
+    let mut temporary = NamedTempFile::new_in(cache_dir)?;
+    io::copy(&mut fs::File::open(path)?, &mut temporary)?;
+    temporary.persist(&output)?;
+    Ok(vec![Imported::New(id, output)])
+}
+
+fn import_zip(path: &Path, cache_dir: &Path) -> StdResult<Vec<Imported>, cache::GeneratorError> {
+    let archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    let entries: Vec<String> = archive
+        .file_names()
+        .filter(|name| name.to_lowercase().ends_with(".hgt"))
+        .map(String::from)
+        .collect();
+    if entries.is_empty() {
+        return Err(cache::GeneratorError::new("archive has no height files"));
+    }
+    let mut cells = Vec::new();
+    for entry in entries {
+        let name = Path::new(&entry)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let id = cell_id(&name).ok_or(cache::GeneratorError::new(&format!(
+            "{} is not named like an srtm cell",
+            entry
+        )))?;
+        if cache_dir.join(&id).exists() {
+            cells.push(Imported::Existing(id));
+            continue;
+        }
+        let output = srtm::extract_entry(cache_dir, path, &entry, &id)?;
+        cells.push(Imported::New(id, output));
+    }
+    Ok(cells)
+}
+
+/// Split a GeoTIFF into one arc second height files for each cell it covers
+///
+/// Cells the raster only partly covers are written with voids outside of it.
+fn import_geotiff(
+    path: &Path,
+    cache_dir: &Path,
+) -> StdResult<Vec<Imported>, cache::GeneratorError> {
+    let extent = regional::raster_info(path)?.bounds;
+    let mut cells = Vec::new();
+    let mut latitude = extent.min_latitude.floor();
+    while latitude < extent.max_latitude {
+        let mut longitude = extent.min_longitude.floor();
+        while longitude < extent.max_longitude {
+            let bounds = elevation::CellBounds {
+                min_longitude: longitude,
+                min_latitude: latitude,
+                max_longitude: longitude + 1.0,
+                max_latitude: latitude + 1.0,
+            };
+            // sample from inside the cell so rounding cannot select a neighbour
+            let id = srtm::srtm_id(&crate::tile::GeoPoint {
+                longitude: longitude + 0.5,
+                latitude: latitude + 0.5,
+            });
+            if cache_dir.join(&id).exists() {
+                cells.push(Imported::Existing(id));
+            } else {
+                let output = warp_cell(path, &bounds, &id, cache_dir)?;
+                cells.push(Imported::New(id, output));
+            }
+            longitude += 1.0;
+        }
+        latitude += 1.0;
+    }
+    Ok(cells)
+}
+
+fn warp_cell(
+    path: &Path,
+    bounds: &elevation::CellBounds,
+    id: &str,
+    cache_dir: &Path,
+) -> ProcessingResult<PathBuf> {
+    log::info!("warping {:?} to {}", path, id);
+    let work = tempfile::tempdir_in(cache_dir)?;
+    let warped = work.path().join("warped.tif");
+    // height file samples are centered on the cell edges
+    let half = 0.5 / 3600.0;
+    let result = process::Command::new("gdalwarp")
+        .arg("-t_srs")
+        .arg("EPSG:4326")
+        .arg("-te")
+        .arg(format!("{}", bounds.min_longitude - half))
+        .arg(format!("{}", bounds.min_latitude - half))
+        .arg(format!("{}", bounds.max_longitude + half))
+        .arg(format!("{}", bounds.max_latitude + half))
+        .arg("-ts")
+        .arg("3601")
+        .arg("3601")
+        .arg("-r")
+        .arg("bilinear")
+        .arg("-ot")
+        .arg("Int16")
+        .arg("-dstnodata")
+        .arg(format!("{}", srtm::VOID_VALUE))
+        .arg(path)
+        .arg(&warped)
+        .output()?;
+    if !result.status.success() {
+        return Err(ProcessingError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    // the SRTMHGT driver needs the output named after the cell
+    let height = work.path().join(id);
+    let result = process::Command::new("gdal_translate")
+        .arg("-of")
+        .arg("SRTMHGT")
+        .arg(&warped)
+        .arg(&height)
+        .output()?;
+    if !result.status.success() {
+        return Err(ProcessingError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    let output = cache_dir.join(id);
+    fs::rename(&height, &output)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_zip(path: &Path, name: &str, content: &[u8]) {
+        let mut writer = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        writer.start_file(name, options).unwrap();
+        io::Write::write_all(&mut writer, content).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn test_cache(cache_dir: &Path) -> cache::Cache {
+        cache::Cache::from_existing_directory(cache_dir.to_path_buf(), 10_000_000_000, 1, 600)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cell_id() {
+        assert_eq!(cell_id("N49W120.hgt").unwrap(), "N49W120.hgt");
+        assert_eq!(cell_id("n49w120.SRTMGL1.hgt.zip").unwrap(), "N49W120.hgt");
+        assert!(cell_id("elevation.hgt").is_none());
+    }
+
+    #[test]
+    fn test_import_directory() {
+        let input = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().join("srtm");
+        fs::create_dir(input.path().join("nested")).unwrap();
+        fs::write(
+            input.path().join("nested/n49w120.hgt"),
+            vec![1u8; 1201 * 1201 * 2],
+        )
+        .unwrap();
+        write_zip(
+            &input.path().join("N50W120.SRTMGL1.hgt.zip"),
+            "N50W120.hgt",
+            &vec![1u8; 1201 * 1201 * 2],
+        );
+        fs::write(input.path().join("N51W120.hgt"), vec![1u8; 1000]).unwrap();
+        fs::write(input.path().join("readme.txt"), "cells").unwrap();
+
+        let mut cache = test_cache(&cache_dir);
+        let report = import(input.path(), &mut cache).unwrap();
+        assert_eq!(report.imported, vec!["N50W120.hgt", "N49W120.hgt"]);
+        assert!(cache.get(&"N49W120.hgt".into()).is_some());
+        assert!(cache.get(&"N50W120.hgt".into()).is_some());
+        assert_eq!(report.rejected.len(), 1);
+        assert!(cache_dir.join("N49W120.hgt").exists());
+        assert!(cache_dir.join("N50W120.hgt").exists());
+        assert!(!cache_dir.join("N51W120.hgt").exists());
+
+        let index = elevation::CellIndex::load(elevation::index_path(&cache_dir));
+        assert_eq!(index.void_fraction("N49W120.hgt"), Some(0.0));
+        assert!(!index.is_absent("N50W120.hgt"));
+
+        let report = import(input.path(), &mut cache).unwrap();
+        assert!(report.imported.is_empty());
+        assert_eq!(report.skipped.len(), 2);
+    }
+
+    #[test]
+    fn test_import_tarball() {
+        let input = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let tarball = input.path().join("cells.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&tarball).unwrap(),
+            flate2::Compression::fast(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let content = vec![1u8; 1201 * 1201 * 2];
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "cells/S05E022.hgt", content.as_slice())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let report = import(&tarball, &mut test_cache(cache_dir.path())).unwrap();
+        assert_eq!(report.imported, vec!["S05E022.hgt"]);
+        assert!(cache_dir.path().join("S05E022.hgt").exists());
+    }
+}
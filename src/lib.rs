@@ -5,10 +5,27 @@ extern crate anyhow;
 #[macro_use]
 mod approx;
 pub mod cache;
+pub mod catalog;
+pub mod change;
+pub mod copernicus;
+pub mod earthdata;
+pub mod elevation;
+pub mod evalscript;
+pub mod import;
+pub mod local;
+pub mod process_api;
 pub mod processing;
+pub mod regional;
+pub mod retry;
+pub mod sar;
+pub mod seed;
 pub mod sentinel;
 pub mod slope;
+pub mod snow;
 pub mod srtm;
+#[cfg(test)]
+mod standin;
 pub mod tile;
 pub mod token;
+pub mod usage;
 pub mod viewer;
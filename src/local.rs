@@ -0,0 +1,106 @@
+use crate::cache;
+use crate::elevation;
+use crate::srtm;
+use crate::tile;
+use std::fs;
+use std::os::unix;
+use std::path::{Path, PathBuf};
+
+const EXTENSIONS: [&str; 3] = ["hgt", "tif", "tiff"];
+
+/// Elevation cells read from a local directory for offline use
+///
+/// Files are named like SRTM cells (`N49W120.hgt`, `N49W120.tif`) and are
+/// linked into the cache as `<cell>.hgt` rather than copied.
+pub struct LocalDirectory {
+    directory: PathBuf,
+}
+
+impl LocalDirectory {
+    pub fn new(directory: PathBuf) -> Self {
+        LocalDirectory { directory }
+    }
+
+    fn find(&self, stem: &str) -> Option<PathBuf> {
+        for extension in EXTENSIONS {
+            for name in [stem.to_string(), stem.to_lowercase()] {
+                let path = self.directory.join(format!("{}.{}", name, extension));
+                if path.exists() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl elevation::ElevationSource for LocalDirectory {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn cell_id(&self, point: &tile::GeoPoint) -> String {
+        srtm::srtm_id(point)
+    }
+
+    fn cell_bounds(&self, id: &str) -> Option<elevation::CellBounds> {
+        srtm::srtm_bounds(id)
+    }
+
+    fn void_value(&self) -> f64 {
+        srtm::VOID_VALUE
+    }
+
+    fn fetch_cell(&self, output_directory: &Path, id: &str) -> cache::CacheResult {
+        let stem = id.strip_suffix(".hgt").ok_or(cache::GeneratorError::new(
+            "key did not contain .hgt suffix",
+        ))?;
+        let source = self
+            .find(stem)
+            .ok_or(cache::GeneratorError::not_found(&format!(
+                "no local elevation file for {} in {:?}",
+                stem, self.directory
+            )))?;
+        // named by the cache key so the link is found again after a restart,
+        // gdal picks the driver from the content rather than the name
+        let output = output_directory.join(id);
+        if output.is_symlink() {
+            fs::remove_file(&output)?;
+        }
+        log::info!("linking local elevation {:?} to {:?}", source, output);
+        unix::fs::symlink(fs::canonicalize(&source)?, &output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevation::ElevationSource;
+    use tempfile;
+
+    #[test]
+    fn test_fetch() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(source_dir.path().join("N49W120.tif"), "tif").unwrap();
+        fs::write(source_dir.path().join("n50w120.hgt"), "hgt").unwrap();
+        let source = LocalDirectory::new(source_dir.path().to_path_buf());
+
+        let output = source.fetch_cell(cache_dir.path(), "N49W120.hgt").unwrap();
+        assert_eq!(output, cache_dir.path().join("N49W120.hgt"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "tif");
+        // fetching again replaces the existing link
+        let output = source.fetch_cell(cache_dir.path(), "N49W120.hgt").unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "tif");
+
+        let output = source.fetch_cell(cache_dir.path(), "N50W120.hgt").unwrap();
+        assert_eq!(output, cache_dir.path().join("N50W120.hgt"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "hgt");
+
+        let error = source
+            .fetch_cell(cache_dir.path(), "N51W120.hgt")
+            .unwrap_err();
+        assert_eq!(error.kind(), &cache::ErrorKind::NotFound);
+    }
+}
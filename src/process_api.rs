@@ -0,0 +1,316 @@
+use serde::Serialize;
+
+/// Coordinate system of request bounds, web mercator like the tiles
+pub const WEB_MERCATOR: &str = "http://www.opengis.net/def/crs/EPSG/0/3857";
+
+/// Body of a Sentinel Hub process API request, see
+/// https://documentation.dataspace.copernicus.eu/APIs/SentinelHub/Process.html
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProcessRequest {
+    pub input: Input,
+    pub output: Output,
+    pub evalscript: String,
+}
+
+impl ProcessRequest {
+    /// Render a web mercator `[min_x, min_y, max_x, max_y]` box as a PNG of
+    /// `width` by `height` pixels along with the script's user data
+    pub fn new(bbox: [f64; 4], width: u32, height: u32, evalscript: &str) -> Self {
+        ProcessRequest {
+            input: Input {
+                bounds: Bounds {
+                    properties: BoundsProperties {
+                        crs: WEB_MERCATOR.to_string(),
+                    },
+                    bbox,
+                },
+                data: Vec::new(),
+            },
+            output: Output {
+                width,
+                height,
+                responses: vec![
+                    Response::new("default", "image/png"),
+                    Response::new("userdata", "application/json"),
+                ],
+            },
+            evalscript: evalscript.to_string(),
+        }
+    }
+
+    pub fn with_data(mut self, data: DataSource) -> Self {
+        self.input.data.push(data);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Input {
+    pub bounds: Bounds,
+    pub data: Vec<DataSource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bounds {
+    pub properties: BoundsProperties,
+    pub bbox: [f64; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BoundsProperties {
+    pub crs: String,
+}
+
+/// A data collection and how its scenes are chosen and processed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataSource {
+    #[serde(rename = "type")]
+    pub collection: String,
+    pub data_filter: DataFilter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing: Option<Processing>,
+}
+
+impl DataSource {
+    /// Scenes of a collection like `sentinel-2-l2a` between two RFC 3339 times
+    pub fn new(collection: &str, from: &str, to: &str) -> Self {
+        DataSource {
+            collection: collection.to_string(),
+            data_filter: DataFilter {
+                time_range: TimeRange {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                },
+                max_cloud_coverage: None,
+                mosaicking_order: None,
+                acquisition_mode: None,
+                polarization: None,
+                resolution: None,
+            },
+            processing: None,
+        }
+    }
+
+    /// Percent of a scene which may be cloud, for optical collections
+    pub fn with_max_cloud_coverage(mut self, max_cloud_coverage: f64) -> Self {
+        self.data_filter.max_cloud_coverage = Some(max_cloud_coverage);
+        self
+    }
+
+    pub fn with_mosaicking_order(mut self, order: MosaickingOrder) -> Self {
+        self.data_filter.mosaicking_order = Some(order);
+        self
+    }
+
+    /// Sentinel-1 acquisition mode, polarization and resolution like `IW`,
+    /// `DV` and `HIGH`
+    pub fn with_radar_filter(mut self, mode: &str, polarization: &str, resolution: &str) -> Self {
+        self.data_filter.acquisition_mode = Some(mode.to_string());
+        self.data_filter.polarization = Some(polarization.to_string());
+        self.data_filter.resolution = Some(resolution.to_string());
+        self
+    }
+
+    pub fn with_processing(mut self, processing: Processing) -> Self {
+        self.processing = Some(processing);
+        self
+    }
+
+    /// Set the methods which are given, keeping any other processing
+    pub fn with_resampling(
+        mut self,
+        upsampling: Option<Resampling>,
+        downsampling: Option<Resampling>,
+    ) -> Self {
+        if upsampling.is_none() && downsampling.is_none() {
+            return self;
+        }
+        let mut processing = self.processing.take().unwrap_or_default();
+        if let Some(method) = upsampling {
+            processing = processing.with_upsampling(method);
+        }
+        if let Some(method) = downsampling {
+            processing = processing.with_downsampling(method);
+        }
+        self.with_processing(processing)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataFilter {
+    pub time_range: TimeRange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cloud_coverage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mosaicking_order: Option<MosaickingOrder>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acquisition_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polarization: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeRange {
+    pub from: String,
+    pub to: String,
+}
+
+/// Order of scenes in a mosaic, which is also the order of the samples given
+/// to scripts using `Mosaicking.ORBIT`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MosaickingOrder {
+    MostRecent,
+    LeastRecent,
+    #[serde(rename = "leastCC")]
+    LeastCloudy,
+}
+
+/// Interpolation used when output pixels are finer or coarser than the data
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Resampling {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
+
+impl Resampling {
+    /// `nearest`, `bilinear` or `bicubic` in any case
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "nearest" => Some(Resampling::Nearest),
+            "bilinear" => Some(Resampling::Bilinear),
+            "bicubic" => Some(Resampling::Bicubic),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resampling::Nearest => "nearest",
+            Resampling::Bilinear => "bilinear",
+            Resampling::Bicubic => "bicubic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Processing {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upsampling: Option<Resampling>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downsampling: Option<Resampling>,
+    /// Sentinel-1 backscatter coefficient like `GAMMA0_TERRAIN`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub back_coeff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orthorectify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dem_instance: Option<String>,
+}
+
+impl Processing {
+    pub fn with_upsampling(mut self, method: Resampling) -> Self {
+        self.upsampling = Some(method);
+        self
+    }
+
+    pub fn with_downsampling(mut self, method: Resampling) -> Self {
+        self.downsampling = Some(method);
+        self
+    }
+
+    /// Terrain flattened backscatter orthorectified with the Copernicus 30 m
+    /// elevation model
+    pub fn with_terrain_correction(mut self) -> Self {
+        self.back_coeff = Some("GAMMA0_TERRAIN".to_string());
+        self.orthorectify = Some(true);
+        self.dem_instance = Some("COPERNICUS_30".to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Output {
+    pub width: u32,
+    pub height: u32,
+    pub responses: Vec<Response>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Response {
+    pub identifier: String,
+    pub format: Format,
+}
+
+impl Response {
+    fn new(identifier: &str, mime: &str) -> Self {
+        Response {
+            identifier: identifier.to_string(),
+            format: Format {
+                mime: mime.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Format {
+    #[serde(rename = "type")]
+    pub mime: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let processing = Processing::default()
+            .with_upsampling(Resampling::Bicubic)
+            .with_downsampling(Resampling::Bilinear);
+        let data = DataSource::new("sentinel-2-l2a", "a", "b")
+            .with_mosaicking_order(MosaickingOrder::LeastCloudy)
+            .with_processing(processing);
+        let value = serde_json::to_value(&data).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "sentinel-2-l2a",
+                "dataFilter": {
+                    "timeRange": {"from": "a", "to": "b"},
+                    "mosaickingOrder": "leastCC"
+                },
+                "processing": {"upsampling": "BICUBIC", "downsampling": "BILINEAR"}
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(MosaickingOrder::MostRecent).unwrap(),
+            "mostRecent"
+        );
+    }
+
+    #[test]
+    fn test_resampling() {
+        assert_eq!(Resampling::parse("BICUBIC"), Some(Resampling::Bicubic));
+        assert_eq!(Resampling::parse("bilinear"), Some(Resampling::Bilinear));
+        assert_eq!(Resampling::parse("cubic"), None);
+        assert_eq!(Resampling::Nearest.name(), "nearest");
+
+        let data = DataSource::new("sentinel-1-grd", "a", "b")
+            .with_processing(Processing::default().with_terrain_correction())
+            .with_resampling(Some(Resampling::Bilinear), None);
+        let processing = data.processing.unwrap();
+        assert_eq!(processing.upsampling, Some(Resampling::Bilinear));
+        assert_eq!(processing.downsampling, None);
+        assert_eq!(processing.orthorectify, Some(true));
+        let data = DataSource::new("sentinel-2-l2a", "a", "b").with_resampling(None, None);
+        assert_eq!(data.processing, None);
+    }
+}
@@ -2,17 +2,59 @@ use serde_json;
 
 pub type ProcessingResult<T> = std::result::Result<T, ProcessingError>;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// asking again will fail the same way
+    Permanent,
+    /// throttled or a transient failure upstream, worth asking again later
+    Retryable,
+    /// the access token was refused, a new one may help
+    Unauthorized,
+}
+
 #[derive(Debug, Clone)]
 pub struct ProcessingError {
     message: String,
+    kind: ErrorKind,
 }
 
 impl ProcessingError {
     pub fn new(message: &str) -> Self {
         ProcessingError {
             message: message.into(),
+            kind: ErrorKind::Permanent,
+        }
+    }
+
+    pub fn retryable(message: &str) -> Self {
+        ProcessingError {
+            message: message.into(),
+            kind: ErrorKind::Retryable,
+        }
+    }
+
+    /// Classify a failed HTTP response by its status
+    pub fn from_status(status: reqwest::StatusCode, message: &str) -> Self {
+        let kind = if status == reqwest::StatusCode::UNAUTHORIZED {
+            ErrorKind::Unauthorized
+        } else if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            ErrorKind::Retryable
+        } else {
+            ErrorKind::Permanent
+        };
+        ProcessingError {
+            message: message.into(),
+            kind,
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind == ErrorKind::Retryable
+    }
 }
 
 impl std::error::Error for ProcessingError {}
@@ -25,6 +67,7 @@ impl From<std::io::Error> for ProcessingError {
     fn from(error: std::io::Error) -> Self {
         ProcessingError {
             message: format!("io: {}", error),
+            kind: ErrorKind::Permanent,
         }
     }
 }
@@ -32,6 +75,7 @@ impl From<std::env::VarError> for ProcessingError {
     fn from(error: std::env::VarError) -> Self {
         ProcessingError {
             message: format!("env: {}", error),
+            kind: ErrorKind::Permanent,
         }
     }
 }
@@ -39,13 +83,20 @@ impl From<serde_json::Error> for ProcessingError {
     fn from(error: serde_json::Error) -> Self {
         ProcessingError {
             message: format!("serde: {}", error),
+            kind: ErrorKind::Permanent,
         }
     }
 }
 impl From<reqwest::Error> for ProcessingError {
     fn from(error: reqwest::Error) -> Self {
+        let kind = match error.status() {
+            Some(status) => ProcessingError::from_status(status, "").kind,
+            None if error.is_timeout() || error.is_connect() => ErrorKind::Retryable,
+            None => ErrorKind::Permanent,
+        };
         ProcessingError {
             message: format!("reqwest: {}", error),
+            kind,
         }
     }
 }
@@ -53,6 +104,7 @@ impl From<image::ImageError> for ProcessingError {
     fn from(error: image::ImageError) -> Self {
         ProcessingError {
             message: format!("image: {}", error),
+            kind: ErrorKind::Permanent,
         }
     }
 }
@@ -60,6 +112,7 @@ impl From<ab_glyph::InvalidFont> for ProcessingError {
     fn from(error: ab_glyph::InvalidFont) -> Self {
         ProcessingError {
             message: format!("font: {}", error),
+            kind: ErrorKind::Permanent,
         }
     }
 }
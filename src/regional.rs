@@ -0,0 +1,299 @@
+use crate::elevation::CellBounds;
+use crate::processing::{ProcessingError, ProcessingResult};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use tempfile::NamedTempFile;
+
+/// Highest zoom level served from regional elevation
+pub const MAX_ZOOM: u8 = 18;
+/// Width in tile pixels of the band over which a regional DEM is blended
+/// into the coarser elevation under it
+const BLEND_PIXELS: u32 = 16;
+/// Web mercator pixel size in meters of a 256 pixel tile at zoom 0
+const ZOOM_0_PIXEL: f64 = 40_075_016.686 / 256.0;
+
+/// Extent and resolution of a raster, from `gdalinfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RasterInfo {
+    pub bounds: CellBounds,
+    /// pixel size in meters
+    pub resolution: f64,
+}
+
+pub fn raster_info(path: &Path) -> ProcessingResult<RasterInfo> {
+    let result = process::Command::new("gdalinfo")
+        .arg("-json")
+        .arg(path)
+        .output()?;
+    if !result.status.success() {
+        return Err(ProcessingError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    parse_info(&serde_json::from_slice(&result.stdout)?)
+}
+
+fn parse_info(info: &serde_json::Value) -> ProcessingResult<RasterInfo> {
+    let points = info["wgs84Extent"]["coordinates"][0]
+        .as_array()
+        .ok_or(ProcessingError::new("raster has no wgs84 extent"))?;
+    let mut bounds = CellBounds {
+        min_longitude: f64::MAX,
+        min_latitude: f64::MAX,
+        max_longitude: f64::MIN,
+        max_latitude: f64::MIN,
+    };
+    for point in points {
+        let longitude = point[0].as_f64().unwrap_or(f64::NAN);
+        let latitude = point[1].as_f64().unwrap_or(f64::NAN);
+        bounds.min_longitude = bounds.min_longitude.min(longitude);
+        bounds.min_latitude = bounds.min_latitude.min(latitude);
+        bounds.max_longitude = bounds.max_longitude.max(longitude);
+        bounds.max_latitude = bounds.max_latitude.max(latitude);
+    }
+    let pixel = info["geoTransform"][1]
+        .as_f64()
+        .ok_or(ProcessingError::new("raster has no geotransform"))?
+        .abs();
+    let wkt = info["coordinateSystem"]["wkt"].as_str().unwrap_or("");
+    let resolution = if wkt.starts_with("GEOGCRS") || wkt.starts_with("GEOGCS") {
+        // degrees of longitude at the center of the raster
+        let latitude = (bounds.min_latitude + bounds.max_latitude) / 2.0;
+        pixel * 111_320.0 * latitude.to_radians().cos()
+    } else if wkt.contains("foot") {
+        pixel * 0.3048
+    } else {
+        pixel
+    };
+    Ok(RasterInfo { bounds, resolution })
+}
+
+/// A high resolution elevation raster covering part of the map
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionalDem {
+    pub path: PathBuf,
+    pub bounds: CellBounds,
+    /// pixel size in meters
+    pub resolution: f64,
+}
+
+impl RegionalDem {
+    pub fn from_file(path: &Path) -> ProcessingResult<Self> {
+        let info = raster_info(path)?;
+        Ok(RegionalDem {
+            path: fs::canonicalize(path)?,
+            bounds: info.bounds,
+            resolution: info.resolution,
+        })
+    }
+
+    pub fn intersects(&self, bounds: &CellBounds) -> bool {
+        bounds.min_longitude < self.bounds.max_longitude
+            && bounds.max_longitude > self.bounds.min_longitude
+            && bounds.min_latitude < self.bounds.max_latitude
+            && bounds.max_latitude > self.bounds.min_latitude
+    }
+
+    pub fn contains(&self, bounds: &CellBounds) -> bool {
+        bounds.min_longitude >= self.bounds.min_longitude
+            && bounds.max_longitude <= self.bounds.max_longitude
+            && bounds.min_latitude >= self.bounds.min_latitude
+            && bounds.max_latitude <= self.bounds.max_latitude
+    }
+
+    /// Highest zoom whose tile pixels are no smaller than the raster's
+    pub fn max_zoom(&self) -> u8 {
+        let latitude = (self.bounds.min_latitude + self.bounds.max_latitude) / 2.0;
+        let ratio = ZOOM_0_PIXEL * latitude.to_radians().cos() / self.resolution;
+        (ratio.log2().floor().max(0.0) as u8).min(MAX_ZOOM)
+    }
+}
+
+/// Regional elevation rasters which take priority over the elevation source
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Registry {
+    #[serde(skip)]
+    path: PathBuf,
+    dems: Vec<RegionalDem>,
+}
+
+impl Registry {
+    pub fn load(path: PathBuf) -> Self {
+        let mut registry = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|error| {
+                log::warn!("ignoring invalid dem registry {:?}: {}", path, error);
+                Registry::default()
+            }),
+            Err(_) => Registry::default(),
+        };
+        log::info!(
+            "loaded dem registry {:?} with {} rasters",
+            path,
+            registry.dems.len()
+        );
+        registry.path = path;
+        registry
+    }
+
+    /// Load the registry named by `FLYTILE_REGIONAL_DEMS`, by default
+    /// `regional.json` in the cache directory
+    pub fn from_env(cache: &Path) -> Self {
+        let path = env::var("FLYTILE_REGIONAL_DEMS")
+            .map(PathBuf::from)
+            .unwrap_or(cache.join("regional.json"));
+        Registry::load(path)
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Add a raster, replacing any earlier registration of the same file
+    pub fn register(&mut self, dem: RegionalDem) {
+        self.dems.retain(|existing| existing.path != dem.path);
+        self.dems.push(dem);
+    }
+
+    pub fn dems(&self) -> &[RegionalDem] {
+        &self.dems
+    }
+
+    /// Rasters overlapping the bounds, finest resolution first
+    pub fn covering(&self, bounds: &CellBounds) -> Vec<RegionalDem> {
+        let mut output: Vec<RegionalDem> = self
+            .dems
+            .iter()
+            .filter(|dem| dem.intersects(bounds))
+            .cloned()
+            .collect();
+        output.sort_by(|a, b| a.resolution.total_cmp(&b.resolution));
+        output
+    }
+
+    /// Highest zoom any raster overlapping the bounds supports
+    pub fn max_zoom(&self, bounds: &CellBounds) -> Option<u8> {
+        self.covering(bounds).iter().map(|dem| dem.max_zoom()).max()
+    }
+}
+
+/// Warp a regional raster over an elevation tile in place
+///
+/// The raster is cut to its extent and blended into the existing elevation
+/// over a band along the edge so there is no step where the sources meet.
+pub fn overlay(dem: &RegionalDem, tile: &Path) -> ProcessingResult<()> {
+    let bounds = &dem.bounds;
+    let mut cutline = NamedTempFile::with_suffix(".geojson")?;
+    let footprint = serde_json::json!({
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "properties": {},
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[
+                    [bounds.min_longitude, bounds.min_latitude],
+                    [bounds.max_longitude, bounds.min_latitude],
+                    [bounds.max_longitude, bounds.max_latitude],
+                    [bounds.min_longitude, bounds.max_latitude],
+                    [bounds.min_longitude, bounds.min_latitude]
+                ]]
+            }
+        }]
+    });
+    serde_json::to_writer(&mut cutline, &footprint)?;
+    let result = process::Command::new("gdalwarp")
+        .arg("-cutline")
+        .arg(cutline.path())
+        .arg("-cutline_srs")
+        .arg("EPSG:4326")
+        .arg("-cblend")
+        .arg(format!("{}", BLEND_PIXELS))
+        .arg("-r")
+        .arg("cubicspline")
+        .arg(&dem.path)
+        .arg(tile)
+        .output()?;
+    if !result.status.success() {
+        return Err(ProcessingError::new(&format!(
+            "{:?}",
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dem(name: &str, resolution: f64, min_longitude: f64) -> RegionalDem {
+        RegionalDem {
+            path: PathBuf::from(name),
+            bounds: CellBounds {
+                min_longitude,
+                min_latitude: 49.0,
+                max_longitude: min_longitude + 0.5,
+                max_latitude: 49.5,
+            },
+            resolution,
+        }
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = serde_json::json!({
+            "geoTransform": [500000.0, 2.0, 0.0, 5500000.0, 0.0, -2.0],
+            "coordinateSystem": {"wkt": "PROJCRS[\"NAD83(CSRS) / UTM zone 11N\"]"},
+            "wgs84Extent": {"type": "Polygon", "coordinates": [[
+                [-118.0, 49.5], [-118.0, 49.4], [-117.8, 49.4], [-117.8, 49.5], [-118.0, 49.5]
+            ]]}
+        });
+        let info = parse_info(&info).unwrap();
+        assert_eq!(info.resolution, 2.0);
+        assert_eq!(info.bounds.min_longitude, -118.0);
+        assert_eq!(info.bounds.max_latitude, 49.5);
+    }
+
+    #[test]
+    fn test_max_zoom() {
+        assert_eq!(dem("a", 1.0, -120.0).max_zoom(), 16);
+        assert_eq!(dem("a", 2.0, -120.0).max_zoom(), 15);
+        assert_eq!(dem("a", 30.0, -120.0).max_zoom(), 11);
+    }
+
+    #[test]
+    fn test_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("regional.json");
+        let mut registry = Registry::load(path.clone());
+        registry.register(dem("coarse.tif", 2.0, -120.0));
+        registry.register(dem("fine.tif", 1.0, -119.8));
+        registry.register(dem("coarse.tif", 2.0, -120.0));
+        registry.save().unwrap();
+
+        let registry = Registry::load(path);
+        assert_eq!(registry.dems().len(), 2);
+        let bounds = CellBounds {
+            min_longitude: -119.7,
+            min_latitude: 49.1,
+            max_longitude: -119.6,
+            max_latitude: 49.2,
+        };
+        let covering = registry.covering(&bounds);
+        assert_eq!(covering[0].path, PathBuf::from("fine.tif"));
+        assert_eq!(covering[1].path, PathBuf::from("coarse.tif"));
+        assert!(covering[0].contains(&bounds));
+        assert_eq!(registry.max_zoom(&bounds), Some(16));
+        let elsewhere = CellBounds {
+            min_longitude: -110.0,
+            min_latitude: 40.0,
+            max_longitude: -109.0,
+            max_latitude: 41.0,
+        };
+        assert_eq!(registry.max_zoom(&elsewhere), None);
+    }
+}
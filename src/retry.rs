@@ -0,0 +1,241 @@
+use crate::processing::{ProcessingError, ProcessingResult};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::env;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Requests sent to Sentinel Hub at once when `FLYTILE_SENTINEL_CONCURRENCY`
+/// is not set
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// A failed attempt and how long upstream asked us to wait before the next
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub error: ProcessingError,
+    pub retry_after: Option<Duration>,
+}
+
+impl<E: Into<ProcessingError>> From<E> for Failure {
+    fn from(error: E) -> Self {
+        Failure {
+            error: error.into(),
+            retry_after: None,
+        }
+    }
+}
+
+/// Seconds to wait from a `Retry-After` header, dates are not supported
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Exponential backoff between attempts at a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    /// attempts made before giving up, including the first
+    pub attempts: u32,
+    /// wait after the first failure, doubled after each one following
+    pub base: Duration,
+    /// longest wait, also capping what `Retry-After` may ask for
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            attempts: 5,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait before attempt number `attempt + 1`, as asked by upstream if it did
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after
+            .unwrap_or_else(|| self.base.saturating_mul(2u32.saturating_pow(attempt)))
+            .min(self.max)
+    }
+
+    /// Call `send` until it succeeds, fails with an error which is not
+    /// retryable or runs out of attempts
+    pub fn run<T, F>(&self, mut send: F) -> ProcessingResult<T>
+    where
+        F: FnMut() -> Result<T, Failure>,
+    {
+        let mut attempt = 0;
+        loop {
+            let failure = match send() {
+                Ok(value) => return Ok(value),
+                Err(failure) => failure,
+            };
+            attempt += 1;
+            if !failure.error.is_retryable() || attempt >= self.attempts {
+                return Err(failure.error);
+            }
+            let delay = self.delay(attempt - 1, failure.retry_after);
+            log::warn!(
+                "attempt {} failed, retrying in {:?}: {}",
+                attempt,
+                delay,
+                failure.error
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Caps how many requests are in flight at once across threads
+#[derive(Debug)]
+pub struct Limit {
+    max: usize,
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A slot taken from a `Limit`, given back when dropped
+pub struct Permit<'a> {
+    limit: &'a Limit,
+}
+
+impl Limit {
+    pub fn new(max: usize) -> Self {
+        Limit {
+            max: max.max(1),
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Limit from `FLYTILE_SENTINEL_CONCURRENCY`
+    pub fn from_env() -> Self {
+        let max = env::var("FLYTILE_SENTINEL_CONCURRENCY")
+            .ok()
+            .and_then(|max| max.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_CONCURRENCY);
+        Limit::new(max)
+    }
+
+    /// Block until a slot is free
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut active = self.active.lock().unwrap();
+        while *active >= self.max {
+            active = self.freed.wait(active).unwrap();
+        }
+        *active += 1;
+        Permit { limit: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.limit.active.lock().unwrap() -= 1;
+        self.limit.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn quick(attempts: u32) -> Backoff {
+        Backoff {
+            attempts,
+            base: Duration::from_millis(1),
+            max: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(0, None), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3, None), Duration::from_secs(8));
+        assert_eq!(backoff.delay(10, None), Duration::from_secs(60));
+        assert_eq!(
+            backoff.delay(3, Some(Duration::from_secs(2))),
+            Duration::from_secs(2)
+        );
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_run() {
+        // throttled twice then answered
+        let mut calls = 0;
+        let result = quick(5).run(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Failure {
+                    error: ProcessingError::from_status(
+                        reqwest::StatusCode::TOO_MANY_REQUESTS,
+                        "slow down",
+                    ),
+                    retry_after: Some(Duration::from_millis(1)),
+                })
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // permanent failures are not retried
+        let mut calls = 0;
+        let result: ProcessingResult<()> = quick(5).run(|| {
+            calls += 1;
+            Err(ProcessingError::new("bad request").into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // transient failures are given up on after the last attempt
+        let mut calls = 0;
+        let result: ProcessingResult<()> = quick(3).run(|| {
+            calls += 1;
+            Err(ProcessingError::retryable("unavailable").into())
+        });
+        assert!(result.unwrap_err().is_retryable());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_limit() {
+        let limit = Arc::new(Limit::new(2));
+        let active = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let (limit, active, most) = (limit.clone(), active.clone(), most.clone());
+                thread::spawn(move || {
+                    let _permit = limit.acquire();
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
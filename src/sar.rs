@@ -0,0 +1,176 @@
+use crate::cache;
+use crate::change;
+use crate::evalscript;
+use crate::processing::ProcessingResult;
+use crate::retry;
+use crate::sentinel;
+use crate::usage;
+use anyhow::Result;
+use image::Rgba;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+// grey scale of the backscatter scripts, see `evalscripts/sar-vv.js`
+const MIN_DB: f64 = -25.0;
+const MAX_DB: f64 = 5.0;
+/// Change in decibels shown at full colour by the ratio layer
+const RATIO_RANGE: f64 = 6.0;
+
+/// Backscatter in decibels of a pixel of the `vv` or `vh` layers
+pub fn decode(pixel: &Rgba<u8>) -> Option<f64> {
+    if pixel[3] == 0 || pixel[0] == 0 {
+        return None;
+    }
+    Some(MIN_DB + (pixel[0] as f64 - 1.0) * (MAX_DB - MIN_DB) / 254.0)
+}
+
+/// Colour the change in backscatter from the first to the second image
+///
+/// The difference in decibels is the log of the ratio of the two. Rougher
+/// surfaces like fresh avalanche debris scatter more and show red, wet snow
+/// scatters less and shows blue.
+pub fn ratio(first: &Path, second: &Path, output: &Path) -> ProcessingResult<()> {
+    change::difference(first, second, output, decode, RATIO_RANGE)
+}
+
+/// Sentinel-1 backscatter tiles and the change in backscatter between dates
+pub struct Sar {
+    imagery: sentinel::Sentinel,
+    cache_dir: PathBuf,
+    cache_tx: mpsc::Sender<(
+        cache::Request,
+        Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    )>,
+}
+
+impl Sar {
+    /// Backscatter is cached in `backscatter` and ratios in `ratio` under the
+    /// cache directory, apart from the optical imagery
+    pub fn new(cache_dir: PathBuf) -> Self {
+        let imagery = sentinel::Sentinel::new(cache_dir.join("backscatter"))
+            .with_scripts(evalscript::Library::sar());
+        let cache_dir = cache_dir.join("ratio");
+        let cache = cache::Cache::from_existing_directory(
+            cache_dir.clone(),
+            10_000_000_000,
+            100_000_000,
+            86400,
+        )
+        .unwrap();
+        Sar {
+            imagery,
+            cache_dir,
+            cache_tx: cache::run_cache(cache),
+        }
+    }
+
+    /// Count processing units in a tally shared with other providers
+    pub fn with_usage(mut self, usage: Arc<usage::Usage>) -> Self {
+        self.imagery = self.imagery.with_usage(usage);
+        self
+    }
+
+    /// Share a cap on requests in flight with other providers
+    pub fn with_limit(mut self, limit: Arc<retry::Limit>) -> Self {
+        self.imagery = self.imagery.with_limit(limit);
+        self
+    }
+
+    pub fn scripts(&self) -> &evalscript::Library {
+        self.imagery.scripts()
+    }
+
+    /// Get a tile rendered by the named backscatter script
+    pub async fn get(
+        &self,
+        zoom: u8,
+        x: u32,
+        y: u32,
+        script: &str,
+        options: &sentinel::ImageryOptions,
+    ) -> Result<PathBuf> {
+        self.imagery.get(zoom, x, y, script, options).await
+    }
+
+    /// Get the change in `vv` or `vh` backscatter from the scene found with
+    /// the `reference` options to the one found with `options`
+    pub async fn get_ratio(
+        &self,
+        zoom: u8,
+        x: u32,
+        y: u32,
+        script: &str,
+        reference: &sentinel::ImageryOptions,
+        options: &sentinel::ImageryOptions,
+    ) -> Result<PathBuf> {
+        if script != "vv" && script != "vh" {
+            return Err(anyhow!("no backscatter ratio for {}", script));
+        }
+        let key = PathBuf::from(script)
+            .join(reference.key())
+            .join(options.key())
+            .join(format!("{}", zoom))
+            .join(format!("{}", x))
+            .join(format!("{}.png", y));
+        let output = self.cache_dir.join(&key);
+        // each date is cached on its own so comparisons can share them
+        let first = self.imagery.get(zoom, x, y, script, reference).await?;
+        let second = self.imagery.get(zoom, x, y, script, options).await?;
+        let generator = move || {
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            ratio(&first, &second, &output)?;
+            Ok(output)
+        };
+        cache::ask(&self.cache_tx, key, generator).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageReader, RgbaImage};
+
+    fn grey(value: u8) -> Rgba<u8> {
+        Rgba([value, value, value, 255])
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(&grey(1)), Some(-25.0));
+        assert_eq!(decode(&grey(255)), Some(5.0));
+        assert_eq!(decode(&Rgba([0, 0, 0, 0])), None);
+    }
+
+    #[test]
+    fn test_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = RgbaImage::new(3, 1);
+        first.put_pixel(0, 0, grey(100));
+        first.put_pixel(1, 0, grey(200));
+        let mut second = RgbaImage::new(3, 1);
+        // 6 dB brighter and darker
+        second.put_pixel(0, 0, grey(100 + 51));
+        second.put_pixel(1, 0, grey(200 - 51));
+        second.put_pixel(2, 0, grey(100));
+        let first_path = dir.path().join("first.png");
+        let second_path = dir.path().join("second.png");
+        let output = dir.path().join("ratio.png");
+        first.save(&first_path).unwrap();
+        second.save(&second_path).unwrap();
+
+        ratio(&first_path, &second_path, &output).unwrap();
+        let result = ImageReader::open(&output)
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(*result.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*result.get_pixel(1, 0), Rgba([0, 0, 255, 255]));
+        // no data in the first image
+        assert_eq!(result.get_pixel(2, 0)[3], 0);
+    }
+}
@@ -0,0 +1,677 @@
+use crate::elevation;
+use crate::evalscript;
+use crate::sentinel;
+use crate::slope;
+use crate::srtm;
+use crate::tile;
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Rough average size of a shaded slope tile, used for dry run estimates
+const SLOPE_TILE_BYTES: u64 = 40_000;
+/// Rough average size of a sentinel imagery tile, used for dry run estimates
+const IMAGERY_TILE_BYTES: u64 = 120_000;
+/// Save progress after this many finished tasks
+const SAVE_INTERVAL: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    Elevation,
+    Slope,
+    Imagery,
+}
+
+impl Layer {
+    pub fn all() -> Vec<Layer> {
+        vec![Layer::Elevation, Layer::Slope, Layer::Imagery]
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Layer::Elevation => "elevation",
+            Layer::Slope => "slope",
+            Layer::Imagery => "imagery",
+        }
+    }
+
+    /// Highest zoom the layer is seeded to
+    pub fn max_zoom(&self) -> u8 {
+        match self {
+            Layer::Imagery => sentinel::MAX_ZOOM,
+            Layer::Elevation | Layer::Slope => tile::MAX_ZOOM,
+        }
+    }
+}
+
+impl FromStr for Layer {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text {
+            "elevation" | "srtm" => Ok(Layer::Elevation),
+            "slope" => Ok(Layer::Slope),
+            "imagery" | "sentinel" => Ok(Layer::Imagery),
+            _ => Err(anyhow!("unknown layer {}", text)),
+        }
+    }
+}
+
+/// Area to seed, either a longitude/latitude box or a polygon
+#[derive(Debug, Clone)]
+pub enum Region {
+    Bounds(elevation::CellBounds),
+    /// exterior ring as (longitude, latitude) pairs
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl Region {
+    /// Parse a box written as `min_longitude,min_latitude,max_longitude,max_latitude`
+    pub fn parse_bbox(text: &str) -> Result<Self> {
+        let values = text
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<f64>, _>>()?;
+        if values.len() != 4 || values[0] >= values[2] || values[1] >= values[3] {
+            return Err(anyhow!(
+                "bbox should be min_longitude,min_latitude,max_longitude,max_latitude"
+            ));
+        }
+        Ok(Region::Bounds(elevation::CellBounds {
+            min_longitude: values[0],
+            min_latitude: values[1],
+            max_longitude: values[2],
+            max_latitude: values[3],
+        }))
+    }
+
+    /// Read the exterior ring of a GeoJSON polygon, feature or the first
+    /// feature of a collection
+    pub fn from_geojson(text: &str) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_str(text)?;
+        let geometry = match json["type"].as_str() {
+            Some("FeatureCollection") => &json["features"][0]["geometry"],
+            Some("Feature") => &json["geometry"],
+            _ => &json,
+        };
+        if geometry["type"].as_str() != Some("Polygon") {
+            return Err(anyhow!("region should be a GeoJSON polygon"));
+        }
+        let ring = geometry["coordinates"][0]
+            .as_array()
+            .ok_or(anyhow!("polygon has no coordinates"))?;
+        let mut points = Vec::new();
+        for point in ring {
+            let longitude = point[0].as_f64().ok_or(anyhow!("bad coordinate"))?;
+            let latitude = point[1].as_f64().ok_or(anyhow!("bad coordinate"))?;
+            points.push((longitude, latitude));
+        }
+        if points.len() < 3 {
+            return Err(anyhow!("polygon needs at least three points"));
+        }
+        Ok(Region::Polygon(points))
+    }
+
+    pub fn bounds(&self) -> elevation::CellBounds {
+        match self {
+            Region::Bounds(bounds) => bounds.clone(),
+            Region::Polygon(points) => {
+                let mut bounds = elevation::CellBounds {
+                    min_longitude: f64::MAX,
+                    min_latitude: f64::MAX,
+                    max_longitude: f64::MIN,
+                    max_latitude: f64::MIN,
+                };
+                for (longitude, latitude) in points {
+                    bounds.min_longitude = bounds.min_longitude.min(*longitude);
+                    bounds.min_latitude = bounds.min_latitude.min(*latitude);
+                    bounds.max_longitude = bounds.max_longitude.max(*longitude);
+                    bounds.max_latitude = bounds.max_latitude.max(*latitude);
+                }
+                bounds
+            }
+        }
+    }
+
+    /// true if any part of the box lies in the region
+    pub fn intersects(&self, other: &elevation::CellBounds) -> bool {
+        let bounds = self.bounds();
+        if other.min_longitude >= bounds.max_longitude
+            || other.max_longitude <= bounds.min_longitude
+            || other.min_latitude >= bounds.max_latitude
+            || other.max_latitude <= bounds.min_latitude
+        {
+            return false;
+        }
+        let points = match self {
+            Region::Bounds(_) => return true,
+            Region::Polygon(points) => points,
+        };
+        let corners = [
+            (other.min_longitude, other.min_latitude),
+            (other.max_longitude, other.min_latitude),
+            (other.max_longitude, other.max_latitude),
+            (other.min_longitude, other.max_latitude),
+        ];
+        if points.iter().any(|point| contains(other, *point))
+            || corners.iter().any(|corner| inside(points, *corner))
+        {
+            return true;
+        }
+        // otherwise an edge of the polygon must cross the box
+        for i in 0..points.len() {
+            let edge = (points[i], points[(i + 1) % points.len()]);
+            for j in 0..corners.len() {
+                if crosses(edge, (corners[j], corners[(j + 1) % corners.len()])) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Bounds(bounds) => write!(
+                f,
+                "{},{},{},{}",
+                bounds.min_longitude,
+                bounds.min_latitude,
+                bounds.max_longitude,
+                bounds.max_latitude
+            ),
+            Region::Polygon(points) => {
+                let points: Vec<String> = points
+                    .iter()
+                    .map(|(longitude, latitude)| format!("{} {}", longitude, latitude))
+                    .collect();
+                write!(f, "{}", points.join(","))
+            }
+        }
+    }
+}
+
+fn contains(bounds: &elevation::CellBounds, (x, y): (f64, f64)) -> bool {
+    x >= bounds.min_longitude
+        && x <= bounds.max_longitude
+        && y >= bounds.min_latitude
+        && y <= bounds.max_latitude
+}
+
+/// even-odd ray casting test
+fn inside(points: &[(f64, f64)], (x, y): (f64, f64)) -> bool {
+    let mut result = false;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        let (xi, yi) = points[i];
+        let (xj, yj) = points[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            result = !result;
+        }
+        j = i;
+    }
+    result
+}
+
+fn crosses(a: ((f64, f64), (f64, f64)), b: ((f64, f64), (f64, f64))) -> bool {
+    let side = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+        (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)
+    };
+    let d1 = side(b.0, b.1, a.0);
+    let d2 = side(b.0, b.1, a.1);
+    let d3 = side(a.0, a.1, b.0);
+    let d4 = side(a.0, a.1, b.1);
+    (d1 > 0.0) != (d2 > 0.0) && (d3 > 0.0) != (d4 > 0.0)
+}
+
+/// Tiles at a zoom level which overlap the region, as (x, y)
+pub fn tiles(region: &Region, zoom: u8) -> Vec<(u32, u32)> {
+    // web mercator does not reach the poles
+    let limit = 85.0511;
+    let bounds = region.bounds();
+    let north_west = tile::square_to_tile(
+        zoom,
+        &tile::geodetic_to_square(&tile::GeoPoint {
+            longitude: bounds.min_longitude,
+            latitude: bounds.max_latitude.min(limit),
+        }),
+    );
+    let south_east = tile::square_to_tile(
+        zoom,
+        &tile::geodetic_to_square(&tile::GeoPoint {
+            longitude: bounds.max_longitude,
+            latitude: bounds.min_latitude.max(-limit),
+        }),
+    );
+    let last = 2_u32.pow(zoom as u32) - 1;
+    let mut output = Vec::new();
+    for x in north_west.x.floor().max(0.0) as u32
+        ..=(south_east.x.ceil() as u32).saturating_sub(1).min(last)
+    {
+        for y in north_west.y.floor().max(0.0) as u32
+            ..=(south_east.y.ceil() as u32).saturating_sub(1).min(last)
+        {
+            if region.intersects(&elevation::CellBounds::from_tile(zoom, x, y)) {
+                output.push((x, y));
+            }
+        }
+    }
+    output
+}
+
+/// Ids of the one degree elevation cells overlapping the region
+pub fn cells(region: &Region, source: &dyn elevation::ElevationSource) -> Vec<String> {
+    let bounds = region.bounds();
+    let mut output = Vec::new();
+    let mut latitude = bounds.min_latitude.floor();
+    while latitude < bounds.max_latitude {
+        let mut longitude = bounds.min_longitude.floor();
+        while longitude < bounds.max_longitude {
+            let cell = elevation::CellBounds {
+                min_longitude: longitude,
+                min_latitude: latitude,
+                max_longitude: longitude + 1.0,
+                max_latitude: latitude + 1.0,
+            };
+            if region.intersects(&cell) {
+                output.push(source.cell_id(&tile::GeoPoint {
+                    longitude: longitude + 0.5,
+                    latitude: latitude + 0.5,
+                }));
+            }
+            longitude += 1.0;
+        }
+        latitude += 1.0;
+    }
+    output
+}
+
+/// A single unit of seeding work
+#[derive(Debug, Clone, PartialEq)]
+pub enum Task {
+    Cell(String),
+    Tile(Layer, u8, u32, u32),
+}
+
+impl Task {
+    /// name used to record the task as done in the seed state
+    pub fn key(&self) -> String {
+        match self {
+            Task::Cell(id) => format!("elevation/{}", id),
+            Task::Tile(layer, zoom, x, y) => format!("{}/{}/{}/{}", layer.name(), zoom, x, y),
+        }
+    }
+}
+
+/// What to seed
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub region: Region,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub layers: Vec<Layer>,
+}
+
+impl Job {
+    /// List every task of the job, elevation cells first so tiles find them cached
+    pub fn tasks(&self, source: &dyn elevation::ElevationSource) -> Vec<Task> {
+        let mut output = Vec::new();
+        if self.layers.contains(&Layer::Elevation) {
+            for id in cells(&self.region, source) {
+                output.push(Task::Cell(id));
+            }
+        }
+        for zoom in self.min_zoom..=self.max_zoom {
+            let tiles = tiles(&self.region, zoom);
+            for layer in [Layer::Slope, Layer::Imagery] {
+                if self.layers.contains(&layer) && zoom <= layer.max_zoom() {
+                    for (x, y) in &tiles {
+                        output.push(Task::Tile(layer, zoom, *x, *y));
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    /// a description of the job, a saved state only applies to the same job
+    fn describe(&self) -> String {
+        let layers: Vec<&str> = self.layers.iter().map(|layer| layer.name()).collect();
+        format!(
+            "{} zoom {}-{} {}",
+            self.region,
+            self.min_zoom,
+            self.max_zoom,
+            layers.join(",")
+        )
+    }
+}
+
+/// Expected cost of seeding a list of tasks
+#[derive(Debug, Default, PartialEq)]
+pub struct Estimate {
+    pub cells: usize,
+    pub slope_tiles: usize,
+    pub imagery_tiles: usize,
+    /// approximate disk usage in bytes
+    pub bytes: u64,
+    pub processing_units: f64,
+}
+
+pub fn estimate(tasks: &[Task]) -> Estimate {
+    let library = evalscript::Library::builtin();
+    let script = library
+        .get(evalscript::DEFAULT)
+        .expect("default evalscript is built in");
+    let mut estimate = Estimate::default();
+    for task in tasks {
+        match task {
+            Task::Cell(_) => {
+                estimate.cells += 1;
+                estimate.bytes += srtm::HGT_SIZES[0];
+            }
+            Task::Tile(Layer::Slope, ..) => {
+                estimate.slope_tiles += 1;
+                estimate.bytes += SLOPE_TILE_BYTES;
+            }
+            Task::Tile(Layer::Imagery, ..) => {
+                estimate.imagery_tiles += 1;
+                estimate.bytes += IMAGERY_TILE_BYTES;
+                estimate.processing_units += sentinel::tile_processing_units(script);
+            }
+            Task::Tile(Layer::Elevation, ..) => {}
+        }
+    }
+    estimate
+}
+
+/// Tasks finished by earlier runs of a job, saved so seeding can resume
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SeedState {
+    #[serde(skip)]
+    path: PathBuf,
+    job: String,
+    done: BTreeSet<String>,
+}
+
+impl SeedState {
+    /// Load the state of a job, starting over if the file is for another job
+    pub fn load(path: PathBuf, job: &Job) -> Self {
+        let job = job.describe();
+        let mut state: SeedState = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        if state.job != job {
+            if !state.done.is_empty() {
+                log::info!("seed state {:?} is for another job, starting over", path);
+            }
+            state.done.clear();
+            state.job = job;
+        }
+        state.path = path;
+        state
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string(self)?)
+    }
+
+    pub fn is_done(&self, task: &Task) -> bool {
+        self.done.contains(&task.key())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    pub total: usize,
+    /// finished in an earlier run
+    pub skipped: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Drives the tile providers to generate every task of a job
+pub struct Seeder {
+    elevation: Option<Arc<elevation::Elevation>>,
+    slope: Option<slope::Pipeline>,
+    sentinel: Option<sentinel::Sentinel>,
+    concurrency: usize,
+}
+
+impl Seeder {
+    pub fn new(concurrency: usize) -> Self {
+        Seeder {
+            elevation: None,
+            slope: None,
+            sentinel: None,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    pub fn with_elevation(mut self, elevation: Arc<elevation::Elevation>) -> Self {
+        self.elevation = Some(elevation);
+        self
+    }
+
+    pub fn with_slope(mut self, slope: slope::Pipeline) -> Self {
+        self.slope = Some(slope);
+        self
+    }
+
+    pub fn with_sentinel(mut self, sentinel: sentinel::Sentinel) -> Self {
+        self.sentinel = Some(sentinel);
+        self
+    }
+
+    /// Run the tasks not already done, calling `report` as each one finishes
+    ///
+    /// Failed tasks are logged and left out of the state so the next run
+    /// retries them.
+    pub async fn run<F>(&self, tasks: Vec<Task>, state: &mut SeedState, mut report: F) -> Progress
+    where
+        F: FnMut(&Progress),
+    {
+        let mut progress = Progress {
+            total: tasks.len(),
+            ..Default::default()
+        };
+        let remaining: Vec<Task> = tasks
+            .into_iter()
+            .filter(|task| !state.is_done(task))
+            .collect();
+        progress.skipped = progress.total - remaining.len();
+        report(&progress);
+        let mut results = stream::iter(remaining)
+            .map(|task| async move {
+                let result = self.run_task(&task).await;
+                (task, result)
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some((task, result)) = results.next().await {
+            match result {
+                Ok(()) => {
+                    state.done.insert(task.key());
+                    progress.done += 1;
+                }
+                Err(error) => {
+                    log::error!("could not seed {}: {}", task.key(), error);
+                    progress.failed += 1;
+                }
+            }
+            if (progress.done + progress.failed).is_multiple_of(SAVE_INTERVAL) {
+                if let Err(error) = state.save() {
+                    log::warn!("could not save seed state: {}", error);
+                }
+            }
+            report(&progress);
+        }
+        if let Err(error) = state.save() {
+            log::warn!("could not save seed state: {}", error);
+        }
+        progress
+    }
+
+    async fn run_task(&self, task: &Task) -> Result<()> {
+        match task {
+            Task::Cell(id) => {
+                let elevation = self.elevation.as_ref().ok_or(anyhow!("no elevation"))?;
+                elevation.get_cell(id).await?;
+            }
+            Task::Tile(Layer::Slope, zoom, x, y) => {
+                let slope = self.slope.as_ref().ok_or(anyhow!("no slope pipeline"))?;
+                slope.get(*zoom, *x, *y).await?;
+            }
+            Task::Tile(Layer::Imagery, zoom, x, y) => {
+                let sentinel = self.sentinel.as_ref().ok_or(anyhow!("no imagery"))?;
+                sentinel
+                    .get(
+                        *zoom,
+                        *x,
+                        *y,
+                        evalscript::DEFAULT,
+                        &sentinel::ImageryOptions::default(),
+                    )
+                    .await?;
+            }
+            Task::Tile(Layer::Elevation, ..) => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::earthdata;
+
+    fn bbox() -> Region {
+        Region::parse_bbox("-119.7,49.4,-119.5,49.6").unwrap()
+    }
+
+    #[test]
+    fn test_tiles() {
+        let tiles = tiles(&bbox(), 10);
+        // the box straddles two tiles at zoom 10
+        assert_eq!(tiles, vec![(171, 349), (172, 349)]);
+        let center = tile::square_to_tile(
+            12,
+            &tile::geodetic_to_square(&tile::GeoPoint {
+                longitude: -119.6,
+                latitude: 49.5,
+            }),
+        );
+        assert!(tiles_contain(&super::tiles(&bbox(), 12), center));
+    }
+
+    fn tiles_contain(tiles: &[(u32, u32)], point: tile::Point) -> bool {
+        tiles.contains(&(point.x as u32, point.y as u32))
+    }
+
+    #[test]
+    fn test_polygon() {
+        // a thin diagonal polygon crosses fewer tiles than its bounding box
+        let region = Region::from_geojson(
+            r#"{"type": "Feature", "geometry": {"type": "Polygon", "coordinates": [
+                [[-120.0, 49.0], [-119.0, 50.0], [-119.01, 50.0], [-120.0, 49.01], [-120.0, 49.0]]
+            ]}}"#,
+        )
+        .unwrap();
+        let all = tiles(&Region::Bounds(region.bounds()), 10);
+        let inside = tiles(&region, 10);
+        assert!(inside.len() < all.len());
+        assert!(!inside.is_empty());
+        assert!(Region::from_geojson(r#"{"type": "Point", "coordinates": [1, 2]}"#).is_err());
+    }
+
+    #[test]
+    fn test_tasks_and_estimate() {
+        let job = Job {
+            region: bbox(),
+            min_zoom: 10,
+            max_zoom: 11,
+            layers: Layer::all(),
+        };
+        let tasks = job.tasks(&srtm::SRTM::new(earthdata::Credentials::Token(
+            "token".into(),
+        )));
+        assert_eq!(tasks[0], Task::Cell("N49W120.hgt".into()));
+        let estimate = estimate(&tasks);
+        assert_eq!(estimate.cells, 1);
+        assert_eq!(estimate.slope_tiles, estimate.imagery_tiles);
+        assert_eq!(
+            estimate.slope_tiles,
+            tasks.len() - 1 - estimate.imagery_tiles
+        );
+        assert_eq!(
+            estimate.processing_units,
+            estimate.imagery_tiles as f64 * 0.25
+        );
+    }
+
+    #[test]
+    fn test_imagery_past_slope_zoom() {
+        let job = Job {
+            region: bbox(),
+            min_zoom: tile::MAX_ZOOM,
+            max_zoom: sentinel::MAX_ZOOM,
+            layers: vec![Layer::Slope, Layer::Imagery],
+        };
+        let tasks = job.tasks(&srtm::SRTM::new(earthdata::Credentials::Token(
+            "token".into(),
+        )));
+        assert!(tasks.iter().all(|task| match task {
+            Task::Tile(Layer::Slope, zoom, ..) => *zoom <= tile::MAX_ZOOM,
+            _ => true,
+        }));
+        assert!(tasks
+            .iter()
+            .any(|task| matches!(task, Task::Tile(Layer::Imagery, zoom, ..) if *zoom == sentinel::MAX_ZOOM)));
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seed.json");
+        let job = Job {
+            region: bbox(),
+            min_zoom: 10,
+            max_zoom: 10,
+            layers: vec![Layer::Slope],
+        };
+        let tasks = job.tasks(&srtm::SRTM::new(earthdata::Credentials::Token(
+            "token".into(),
+        )));
+        let mut state = SeedState::load(path.clone(), &job);
+        // no pipeline so every task fails and stays to be retried
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let seeder = Seeder::new(2);
+        let progress = runtime.block_on(seeder.run(tasks.clone(), &mut state, |_| {}));
+        assert_eq!(progress.failed, tasks.len());
+
+        state.done.insert(tasks[0].key());
+        state.save().unwrap();
+        let mut state = SeedState::load(path.clone(), &job);
+        assert!(state.is_done(&tasks[0]));
+        let progress = runtime.block_on(seeder.run(tasks.clone(), &mut state, |_| {}));
+        assert_eq!(progress.skipped, 1);
+        assert_eq!(progress.failed, tasks.len() - 1);
+
+        let other = Job {
+            max_zoom: 11,
+            ..job
+        };
+        assert!(!SeedState::load(path, &other).is_done(&tasks[0]));
+    }
+}
@@ -1,45 +1,418 @@
 use crate::cache;
+use crate::evalscript;
+use crate::process_api;
 use crate::processing::ProcessingError;
+use crate::retry;
 use crate::tile;
 use crate::token;
+use crate::usage;
 use anyhow::Result;
+use image::DynamicImage;
 use image::ImageFormat;
 use image::ImageReader;
 use image::Rgba;
+use image::RgbaImage;
 use imageproc::drawing::draw_text_mut;
 use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
 use serde_json;
 use std::env;
 use std::fs;
 use std::io::Cursor;
 use std::io::Read;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::time::Duration;
 use tar::Archive;
+use time::format_description::well_known::Rfc3339;
+use time::Date;
 use time::OffsetDateTime;
 
 const URL: &'static str = "https://sh.dataspace.copernicus.eu/api/v1/process";
-const TOKEN_URL: &'static str =
+pub const TOKEN_URL: &str =
     "https://identity.dataspace.copernicus.eu/auth/realms/CDSE/protocol/openid-connect/token";
-const IMAGE_SCRIPT: &'static str = r#"//VERSION=3
-
-function setup() {
-  return {
-    input: ["B02", "B03", "B04"],
-    mosaicking: Mosaicking.ORBIT,
-    output: { id:"default", bands: 3}
-  }
+/// Width and height of requested imagery tiles in pixels
+pub const TILE_SIZE: u32 = 256;
+/// Finest imagery zoom, beyond 14 tiles are finer than the 10 m bands and are
+/// resampled
+pub const MAX_ZOOM: u8 = 17;
+
+/// Estimate the Sentinel Hub processing units charged for one request
+///
+/// One unit is a 512x512 pixel output from three input bands, with a minimum
+/// area factor of 0.01, see
+/// https://documentation.dataspace.copernicus.eu/APIs/SentinelHub/Overview/ProcessingUnit.html
+pub fn estimate_processing_units(width: u32, height: u32, input_bands: u32) -> f64 {
+    let area = (width as f64 * height as f64 / (512.0 * 512.0)).max(0.01);
+    area * input_bands as f64 / 3.0
+}
+
+/// Processing units charged for one imagery tile rendered by a script
+pub fn tile_processing_units(script: &evalscript::Evalscript) -> f64 {
+    estimate_processing_units(TILE_SIZE, TILE_SIZE, script.bands)
+}
+
+const DEFAULT_MAX_CLOUD_COVERAGE: f64 = 30.0;
+/// Days searched back for imagery when no start date is given
+const SEARCH_DAYS: u64 = 30;
+
+/// Dates to search for imagery, the most recent image in the range is used
+#[derive(Debug, Clone, PartialEq)]
+pub enum DateRange {
+    /// up to now
+    Latest,
+    /// up to the start of a day
+    Before(Date),
+    /// from the start of the first day to the end of the second
+    Between(Date, Date),
+}
+
+/// Which scene in the date range is preferred
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneOrder {
+    MostRecent,
+    LeastCloudy,
+}
+
+impl SceneOrder {
+    fn mosaicking_order(&self) -> process_api::MosaickingOrder {
+        match self {
+            SceneOrder::MostRecent => process_api::MosaickingOrder::MostRecent,
+            SceneOrder::LeastCloudy => process_api::MosaickingOrder::LeastCloudy,
+        }
+    }
+}
+
+/// Where imagery comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImagerySource {
+    Sentinel,
+    Landsat,
+    /// Sentinel, or Landsat when no Sentinel scene is found
+    SentinelThenLandsat,
+}
+
+/// Search parameters for imagery tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageryOptions {
+    /// percent of a scene which may be cloud
+    pub max_cloud_coverage: f64,
+    pub dates: DateRange,
+    pub order: SceneOrder,
+    /// draw the acquisition date on the tile
+    pub stamp: bool,
+    /// interpolation when tiles are finer than the data, the script's when none
+    pub upsampling: Option<process_api::Resampling>,
+    /// interpolation when tiles are coarser than the data
+    pub downsampling: Option<process_api::Resampling>,
+    pub source: ImagerySource,
+}
+
+impl Default for ImageryOptions {
+    fn default() -> Self {
+        ImageryOptions {
+            max_cloud_coverage: DEFAULT_MAX_CLOUD_COVERAGE,
+            dates: DateRange::Latest,
+            order: SceneOrder::MostRecent,
+            stamp: false,
+            upsampling: None,
+            downsampling: None,
+            source: ImagerySource::Sentinel,
+        }
+    }
+}
+
+impl ImageryOptions {
+    /// Check options given as `YYYY-MM-DD` dates, `before` can not be combined
+    /// with `from` and `to` which must be given together. `order` is `recent`
+    /// or `least-cloudy`.
+    pub fn parse(
+        cloud: Option<f64>,
+        from: Option<&str>,
+        to: Option<&str>,
+        before: Option<&str>,
+        order: Option<&str>,
+    ) -> std::result::Result<Self, ProcessingError> {
+        let order = match order {
+            None | Some("recent") => SceneOrder::MostRecent,
+            Some("least-cloudy") => SceneOrder::LeastCloudy,
+            Some(order) => {
+                return Err(ProcessingError::new(&format!(
+                    "order should be recent or least-cloudy, not {}",
+                    order
+                )))
+            }
+        };
+        let max_cloud_coverage = cloud.unwrap_or(DEFAULT_MAX_CLOUD_COVERAGE);
+        if !(0.0..=100.0).contains(&max_cloud_coverage) {
+            return Err(ProcessingError::new(
+                "cloud coverage should be between 0 and 100",
+            ));
+        }
+        let dates = match (from, to, before) {
+            (None, None, None) => DateRange::Latest,
+            (None, None, Some(before)) => DateRange::Before(parse_date(before)?),
+            (Some(from), Some(to), None) => {
+                let from = parse_date(from)?;
+                let to = parse_date(to)?;
+                if from > to {
+                    return Err(ProcessingError::new("from should not be after to"));
+                }
+                DateRange::Between(from, to)
+            }
+            (_, _, Some(_)) => {
+                return Err(ProcessingError::new(
+                    "before can not be combined with from and to",
+                ))
+            }
+            _ => return Err(ProcessingError::new("from and to should be given together")),
+        };
+        Ok(ImageryOptions {
+            max_cloud_coverage,
+            dates,
+            order,
+            stamp: false,
+            upsampling: None,
+            downsampling: None,
+            source: ImagerySource::Sentinel,
+        })
+    }
+
+    pub fn with_stamp(mut self, stamp: bool) -> Self {
+        self.stamp = stamp;
+        self
+    }
+
+    /// Check resampling methods given as `nearest`, `bilinear` or `bicubic`
+    pub fn with_resampling(
+        mut self,
+        upsampling: Option<&str>,
+        downsampling: Option<&str>,
+    ) -> std::result::Result<Self, ProcessingError> {
+        let parse = |name: Option<&str>| match name {
+            None => Ok(None),
+            Some(name) => {
+                process_api::Resampling::parse(name)
+                    .map(Some)
+                    .ok_or(ProcessingError::new(&format!(
+                        "resampling should be nearest, bilinear or bicubic, not {}",
+                        name
+                    )))
+            }
+        };
+        self.upsampling = parse(upsampling)?;
+        self.downsampling = parse(downsampling)?;
+        Ok(self)
+    }
+
+    /// Check a source given as `sentinel`, `landsat` or `fallback` for
+    /// Landsat where Sentinel has no scene
+    pub fn with_source(
+        mut self,
+        source: Option<&str>,
+    ) -> std::result::Result<Self, ProcessingError> {
+        self.source = match source {
+            None | Some("sentinel") => ImagerySource::Sentinel,
+            Some("landsat") => ImagerySource::Landsat,
+            Some("fallback") => ImagerySource::SentinelThenLandsat,
+            Some(source) => {
+                return Err(ProcessingError::new(&format!(
+                    "source should be sentinel, landsat or fallback, not {}",
+                    source
+                )))
+            }
+        };
+        Ok(self)
+    }
+
+    /// Cache directory for tiles made with these options
+    pub fn key(&self) -> PathBuf {
+        let dates = match &self.dates {
+            DateRange::Latest => "latest".to_string(),
+            DateRange::Before(before) => format!("before-{}", before),
+            DateRange::Between(from, to) => format!("{}_{}", from, to),
+        };
+        let mut key = PathBuf::from(dates).join(format!("cloud-{}", self.max_cloud_coverage));
+        if self.order == SceneOrder::LeastCloudy {
+            key.push("least-cloudy");
+        }
+        match self.source {
+            ImagerySource::Sentinel => (),
+            ImagerySource::Landsat => key.push("landsat"),
+            ImagerySource::SentinelThenLandsat => key.push("landsat-fallback"),
+        }
+        if let Some(method) = self.upsampling {
+            key.push(format!("upsample-{}", method.name()));
+        }
+        if let Some(method) = self.downsampling {
+            key.push(format!("downsample-{}", method.name()));
+        }
+        if self.stamp {
+            key.push("stamped");
+        }
+        key
+    }
+
+    /// Start and end of the search
+    pub fn time_range(&self, now: OffsetDateTime) -> (OffsetDateTime, OffsetDateTime) {
+        let search = Duration::from_secs(3600 * 24 * SEARCH_DAYS);
+        match &self.dates {
+            DateRange::Latest => (now - search, now),
+            DateRange::Before(before) => {
+                let end = before.midnight().assume_utc();
+                (end - search, end)
+            }
+            DateRange::Between(from, to) => (
+                from.midnight().assume_utc(),
+                to.midnight().assume_utc() + Duration::from_secs(3600 * 24),
+            ),
+        }
+    }
+}
+
+fn parse_date(text: &str) -> std::result::Result<Date, ProcessingError> {
+    Date::parse(
+        text,
+        time::macros::format_description!("[year]-[month]-[day]"),
+    )
+    .map_err(|_| ProcessingError::new(&format!("{} is not a YYYY-MM-DD date", text)))
 }
 
-function updateOutputMetadata(scenes, inputMetadata, outputMetadata) {
-    outputMetadata.userData = { "scenes":  scenes.orbits }
+/// An orbit of scenes used to render a tile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    /// acquisition date like `2025-02-01`
+    pub date: String,
+    /// relative orbit number, read from Sentinel-2 product ids
+    pub orbit: Option<u32>,
+    /// ids of the products in the orbit
+    pub tiles: Vec<String>,
+    /// mean cloud coverage in percent of the products, if known
+    pub cloud_coverage: Option<f64>,
 }
 
-function evaluatePixel(samples) {
-  return [ 2.5 * samples[0].B04, 2.5 * samples[0].B03, 2.5 * samples[0].B02 ]
-}"#;
+/// Description of an imagery tile, stored next to it in the cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneMetadata {
+    /// date of the first scene, which most of the tile comes from
+    pub date: Option<String>,
+    /// scenes in mosaicking order
+    pub scenes: Vec<Scene>,
+    /// RFC 3339 time the tile was made
+    pub generated: String,
+    /// collection the scenes are from, like `sentinel-2-l1c` or `landsat-ot-l2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl SceneMetadata {
+    /// Read the `userdata.json` of a process API response from scripts which
+    /// return `scenes.orbits` as their user data
+    pub fn from_userdata(userdata: &str) -> std::result::Result<Self, ProcessingError> {
+        let json: serde_json::Value = serde_json::from_str(userdata)?;
+        let orbits = json["scenes"]
+            .as_array()
+            .ok_or(ProcessingError::new("user data has no scenes"))?;
+        let scenes: Vec<Scene> = orbits.iter().filter_map(parse_scene).collect();
+        Ok(SceneMetadata {
+            date: scenes.first().map(|scene| scene.date.clone()),
+            scenes,
+            generated: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            source: None,
+        })
+    }
+
+    /// Cloud coverage of the first scene
+    pub fn cloud_coverage(&self) -> Option<f64> {
+        self.scenes.first().and_then(|scene| scene.cloud_coverage)
+    }
+}
+
+fn parse_scene(orbit: &serde_json::Value) -> Option<Scene> {
+    let date = orbit["dateFrom"].as_str()?.get(..10)?.to_string();
+    let tiles = orbit["tiles"].as_array().cloned().unwrap_or_default();
+    let ids: Vec<String> = tiles
+        .iter()
+        .filter_map(|tile| tile["tileOriginalId"].as_str().map(String::from))
+        .collect();
+    let clouds: Vec<f64> = tiles
+        .iter()
+        .filter_map(|tile| tile["cloudCoverage"].as_f64())
+        .collect();
+    let cloud_coverage = if clouds.is_empty() {
+        None
+    } else {
+        Some(clouds.iter().sum::<f64>() / clouds.len() as f64)
+    };
+    Some(Scene {
+        date,
+        orbit: ids.first().and_then(|id| relative_orbit(id)),
+        tiles: ids,
+        cloud_coverage,
+    })
+}
+
+/// Relative orbit from a Sentinel-2 product id like
+/// `S2B_MSIL2A_20250201T185539_N0511_R113_T10UFU_20250201T211538`
+fn relative_orbit(id: &str) -> Option<u32> {
+    id.split('_')
+        .find(|part| part.len() == 4 && part.starts_with('R'))
+        .and_then(|part| part[1..].parse().ok())
+}
+
+/// Path of the metadata stored alongside a tile
+pub fn metadata_path(tile: &Path) -> PathBuf {
+    tile.with_extension("json")
+}
+
+pub fn read_metadata(tile: &Path) -> Option<SceneMetadata> {
+    let text = fs::read_to_string(metadata_path(tile)).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// Tiles along each side of the block requested at once
+pub const METATILE_SIZE: u32 = 4;
+
+/// A square block of tiles fetched in one process API request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metatile {
+    pub zoom: u8,
+    /// column of the first tile
+    pub x: u32,
+    /// row of the first tile
+    pub y: u32,
+    /// tiles along each side
+    pub size: u32,
+}
+
+impl Metatile {
+    pub fn containing(zoom: u8, x: u32, y: u32, size: u32) -> Self {
+        Metatile {
+            zoom,
+            x: x - x % size,
+            y: y - y % size,
+            size,
+        }
+    }
+
+    /// Columns and rows of the tiles in the block
+    pub fn tiles(&self) -> Vec<(u32, u32)> {
+        (self.y..self.y + self.size)
+            .flat_map(|y| (self.x..self.x + self.size).map(move |x| (x, y)))
+            .collect()
+    }
+
+    /// Web mercator `[min_x, min_y, max_x, max_y]` of the block
+    fn bbox(&self) -> [f64; 4] {
+        let (x, y, size) = (self.x as f64, self.y as f64, self.size as f64);
+        let nw = tile::square_to_meters(&tile::tile_to_square(self.zoom, x, y));
+        let se = tile::square_to_meters(&tile::tile_to_square(self.zoom, x + size, y + size));
+        [nw.x, se.y, se.x, nw.y]
+    }
+}
 
 pub struct Sentinel {
     cache_dir: PathBuf,
@@ -48,6 +421,13 @@ pub struct Sentinel {
         Box<dyn FnOnce() -> cache::CacheResult + Send>,
     )>,
     token_generator: token::Generator,
+    process_url: String,
+    scripts: evalscript::Library,
+    /// scripts of the same names used for Landsat imagery
+    landsat_scripts: evalscript::Library,
+    metatile_size: u32,
+    usage: Arc<usage::Usage>,
+    limit: Arc<retry::Limit>,
 }
 
 impl Sentinel {
@@ -63,78 +443,352 @@ impl Sentinel {
             cache_dir,
             cache_tx: cache::run_cache(cache),
             token_generator: token::Generator::new(TOKEN_URL),
+            process_url: URL.to_string(),
+            scripts: evalscript::Library::builtin(),
+            landsat_scripts: evalscript::Library::landsat(),
+            metatile_size: METATILE_SIZE,
+            usage: Arc::new(usage::Usage::unlimited()),
+            limit: Arc::new(retry::Limit::from_env()),
         }
     }
 
-    pub async fn get(&self, zoom: u8, x: u32, y: u32) -> Result<PathBuf> {
-        let key = PathBuf::new()
-            .join(format!("{}", zoom))
+    /// Send process API requests to `process_url` with tokens from
+    /// `token_generator` instead of the Copernicus Data Space ones
+    pub fn with_endpoints<T: Into<String>>(
+        mut self,
+        process_url: T,
+        token_generator: token::Generator,
+    ) -> Self {
+        self.process_url = process_url.into();
+        self.token_generator = token_generator;
+        self
+    }
+
+    /// Render imagery with these scripts instead of the built in ones
+    pub fn with_scripts(mut self, scripts: evalscript::Library) -> Self {
+        self.scripts = scripts;
+        self
+    }
+
+    /// Render Landsat imagery with these scripts instead of the built in ones
+    pub fn with_landsat_scripts(mut self, scripts: evalscript::Library) -> Self {
+        self.landsat_scripts = scripts;
+        self
+    }
+
+    /// Fetch blocks of `size` by `size` tiles at once, 1 fetches single tiles
+    pub fn with_metatile_size(mut self, size: u32) -> Self {
+        self.metatile_size = size.max(1);
+        self
+    }
+
+    /// Count processing units in a tally shared with other providers
+    pub fn with_usage(mut self, usage: Arc<usage::Usage>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Share a cap on requests in flight with other providers
+    pub fn with_limit(mut self, limit: Arc<retry::Limit>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn scripts(&self) -> &evalscript::Library {
+        &self.scripts
+    }
+
+    pub fn usage(&self) -> &usage::Usage {
+        &self.usage
+    }
+
+    /// Get a tile rendered by the named script
+    ///
+    /// Fails with [`usage::BudgetExceeded`] if the tile would need a new
+    /// request once the monthly budget is spent.
+    pub async fn get(
+        &self,
+        zoom: u8,
+        x: u32,
+        y: u32,
+        script: &str,
+        options: &ImageryOptions,
+    ) -> Result<PathBuf> {
+        let sentinel = || {
+            self.scripts
+                .get(script)
+                .cloned()
+                .ok_or(anyhow!("unknown evalscript {}", script))
+        };
+        let landsat = || {
+            self.landsat_scripts
+                .get(script)
+                .cloned()
+                .ok_or(anyhow!("no landsat evalscript {}", script))
+        };
+        let (script, fallback) = match options.source {
+            ImagerySource::Sentinel => (sentinel()?, None),
+            ImagerySource::Landsat => (landsat()?, None),
+            // only fall back where there is a Landsat script of the same name
+            ImagerySource::SentinelThenLandsat => {
+                (sentinel()?, self.landsat_scripts.get(script).cloned())
+            }
+        };
+        let job = MetatileJob {
+            cache_dir: self.cache_dir.clone(),
+            cache_tx: self.cache_tx.clone(),
+            process_url: self.process_url.clone(),
+            metatile: Metatile::containing(zoom, x, y, self.metatile_size),
+            script,
+            fallback,
+            options: options.clone(),
+            token: self.token_generator.get().await?,
+            usage: self.usage.clone(),
+            limit: self.limit.clone(),
+        };
+        if !self.cache_dir.join(job.tile_key(x, y)).is_file()
+            && !self.cache_dir.join(job.metatile_key()).is_file()
+        {
+            self.usage.check()?;
+        }
+        match self.ask(job.clone(), x, y).await {
+            Err(error) if is_unauthorized(&error) => {
+                // the token expired mid request, refresh it and try once more
+                log::warn!("token refused, retrying with a new one: {}", error);
+                let token = self.token_generator.refresh(&job.token).await?;
+                self.ask(MetatileJob { token, ..job }, x, y).await
+            }
+            result => result,
+        }
+    }
+
+    async fn ask(&self, job: MetatileJob, x: u32, y: u32) -> Result<PathBuf> {
+        let key = job.tile_key(x, y);
+        cache::ask(&self.cache_tx, key, move || job.tile(x, y)).await
+    }
+}
+
+fn is_unauthorized(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<cache::GeneratorError>()
+        .is_some_and(|error| error.kind() == &cache::ErrorKind::Unauthorized)
+}
+
+/// Everything needed to fetch a metatile and cut tiles from it
+#[derive(Clone)]
+struct MetatileJob {
+    cache_dir: PathBuf,
+    cache_tx: mpsc::Sender<(
+        cache::Request,
+        Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    )>,
+    process_url: String,
+    metatile: Metatile,
+    script: evalscript::Evalscript,
+    /// script rendered instead when the first finds no scene
+    fallback: Option<evalscript::Evalscript>,
+    options: ImageryOptions,
+    token: String,
+    usage: Arc<usage::Usage>,
+    limit: Arc<retry::Limit>,
+}
+
+impl MetatileJob {
+    fn tile_key(&self, x: u32, y: u32) -> PathBuf {
+        PathBuf::from(&self.script.name)
+            .join(self.options.key())
+            .join(format!("{}", self.metatile.zoom))
             .join(format!("{}", x))
-            .join(format!("{}.png", y));
-        let out_path = self.cache_dir.join(&key);
-        let token = self.token_generator.get().await?;
-        let generator = move || generate_tile(out_path, zoom, x, y, token);
+            .join(format!("{}.png", y))
+    }
+
+    /// The date stamp is drawn on each tile so stamped and plain tiles share
+    /// their metatile
+    fn metatile_key(&self) -> PathBuf {
+        PathBuf::from(&self.script.name)
+            .join(self.options.clone().with_stamp(false).key())
+            .join(format!("meta-{}", self.metatile.size))
+            .join(format!("{}", self.metatile.zoom))
+            .join(format!("{}", self.metatile.x))
+            .join(format!("{}.png", self.metatile.y))
+    }
+
+    /// Get one tile, asking the cache for its metatile so concurrent requests
+    /// for tiles of the same metatile wait on a single download
+    fn tile(self, x: u32, y: u32) -> cache::CacheResult {
+        let output = self.cache_dir.join(self.tile_key(x, y));
         let (tx, rx) = mpsc::channel();
+        let request = cache::Request {
+            key: self.metatile_key(),
+            send_back: tx,
+        };
+        let job = self.clone();
         self.cache_tx
-            .send((cache::Request { key, send_back: tx }, Box::new(generator)))
-            .unwrap();
-        Ok(rx.recv()??)
+            .send((request, Box::new(move || job.fetch())))
+            .map_err(|_| cache::GeneratorError::new("imagery cache stopped"))?;
+        let metatile = rx
+            .recv()
+            .map_err(|_| cache::GeneratorError::new("imagery cache stopped"))??;
+        if !output.is_file() {
+            // the tile was evicted while its metatile stayed cached
+            let metadata = read_metadata(&metatile)
+                .ok_or(cache::GeneratorError::new("metatile has no metadata"))?;
+            let image = ImageReader::open(&metatile)
+                .map_err(ProcessingError::from)?
+                .decode()
+                .map_err(ProcessingError::from)?;
+            self.cut(&image, &metadata, x, y)?;
+        }
+        Ok(output)
     }
-}
 
-fn generate_tile(out_path: PathBuf, zoom: u8, x: u32, y: u32, token: String) -> cache::CacheResult {
-    let nw = tile::square_to_meters(&tile::tile_to_square(zoom, x as f64, y as f64));
-    let se = tile::square_to_meters(&tile::tile_to_square(zoom, x as f64 + 1.0, y as f64 + 1.0));
-    let now = OffsetDateTime::now_utc();
-    let before = now - Duration::from_secs(3600 * 24 * 30);
-    let request = format_request(nw.x, se.y, se.x, nw.y, before, now, 30.0);
-    let (meta, image) = download(request, token)?;
-    let date = extract_date(&meta)?;
-    let new_image = add_text(&image, &date)?;
-    let parent = out_path.parent().expect("output should have parent dir");
-    if !parent.exists() {
-        fs::create_dir_all(parent)?;
-    }
-    let mut outfile = fs::File::create(&out_path)?;
-    outfile.write_all(&new_image)?;
-    Ok(out_path)
-}
+    /// Download the metatile and cut every tile from it
+    fn fetch(self) -> cache::CacheResult {
+        let (mut metadata, mut image) = self.render(&self.script)?;
+        if let (true, Some(fallback)) = (metadata.scenes.is_empty(), &self.fallback) {
+            log::info!(
+                "no {} scene for {:?}, trying {}",
+                self.script.collection,
+                self.metatile,
+                fallback.collection
+            );
+            (metadata, image) = self.render(fallback)?;
+        }
+        let path = self.cache_dir.join(self.metatile_key());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(metadata_path(&path), serde_json::to_string(&metadata)?)?;
+        fs::write(&path, &image)?;
+        let image = image::load_from_memory(&image).map_err(ProcessingError::from)?;
+        for (x, y) in self.metatile.tiles() {
+            self.cut(&image, &metadata, x, y)?;
+            // offer the tile to the cache so it is counted before it is asked for
+            let key = self.tile_key(x, y);
+            let tile = self.cache_dir.join(&key);
+            let (tx, _) = mpsc::channel();
+            let _ = self.cache_tx.send((
+                cache::Request { key, send_back: tx },
+                Box::new(move || Ok(tile)),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Request the metatile rendered by a script, returning its metadata and
+    /// PNG image
+    fn render(
+        &self,
+        script: &evalscript::Evalscript,
+    ) -> std::result::Result<(SceneMetadata, Vec<u8>), ProcessingError> {
+        let pixels = TILE_SIZE * self.metatile.size;
+        let request = format_request(
+            self.metatile.bbox(),
+            pixels,
+            self.options.time_range(OffsetDateTime::now_utc()),
+            &self.options,
+            script,
+        );
+        let (meta, image, spent) = download(
+            &self.process_url,
+            serde_json::to_string(&request)?,
+            &self.token,
+            &self.limit,
+        )?;
+        self.usage.record(
+            estimate_processing_units(pixels, pixels, script.bands),
+            spent,
+        );
+        let mut metadata = SceneMetadata::from_userdata(&meta)?;
+        metadata.source = Some(script.collection.clone());
+        Ok((metadata, image))
+    }
 
-fn extract_date(meta: &str) -> std::result::Result<String, ProcessingError> {
-    let json: serde_json::Value = serde_json::from_str(meta)?;
-    let date = json["scenes"][0]["dateFrom"]
-        .as_str()
-        .ok_or(ProcessingError::new("can not convert date"))?;
-    Ok(date[..10].to_string())
+    /// Save one tile of the metatile image along with its metadata
+    fn cut(
+        &self,
+        image: &DynamicImage,
+        metadata: &SceneMetadata,
+        x: u32,
+        y: u32,
+    ) -> std::result::Result<(), ProcessingError> {
+        let column = x - self.metatile.x;
+        let row = y - self.metatile.y;
+        let mut tile = image
+            .crop_imm(column * TILE_SIZE, row * TILE_SIZE, TILE_SIZE, TILE_SIZE)
+            .to_rgba8();
+        if let (Some(date), true) = (&metadata.date, self.options.stamp) {
+            add_text(&mut tile, date)?;
+        }
+        let output = self.cache_dir.join(self.tile_key(x, y));
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(metadata_path(&output), serde_json::to_string(metadata)?)?;
+        tile.save_with_format(&output, ImageFormat::Png)?;
+        Ok(())
+    }
 }
 
+/// Header Sentinel Hub reports the processing units charged for a request in
+const UNITS_SPENT_HEADER: &str = "x-processingunits-spent";
+
+/// Send a process API request, returning the user data, the image and the
+/// processing units charged if they were reported
+///
+/// Throttled and transiently failed requests are retried with backoff, at
+/// most `limit` requests being sent at once.
 fn download(
+    url: &str,
     json_body: String,
-    token: String,
-) -> std::result::Result<(String, Vec<u8>), ProcessingError> {
-    log::debug!("sentinel download request {}", request);
+    token: &str,
+    limit: &retry::Limit,
+) -> std::result::Result<(String, Vec<u8>, Option<f64>), ProcessingError> {
+    log::debug!("sentinel download request {}", json_body);
     let client = reqwest::blocking::Client::builder()
         .timeout(Duration::from_secs(180))
         .build()?;
-    let to_send = client
-        .post(URL)
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .header(ACCEPT, "application/tar")
-        .header(CONTENT_TYPE, "application/json")
-        .body(json_body)
-        .build()?;
+    retry::Backoff::default().run(|| {
+        let _permit = limit.acquire();
+        let to_send = client
+            .post(url)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/tar")
+            .header(CONTENT_TYPE, "application/json")
+            .body(json_body.clone())
+            .build()?;
 
-    log::debug!("send headers {:?}", to_send.headers());
-    log::debug!("send body {:?}", to_send.body());
-    let response = client.execute(to_send)?;
-    log::debug!("response status {:?}", response.status());
-    log::debug!("response headers {:?}", response.headers());
-    log::debug!("response url {:?}", response.url());
-    if response.status() != reqwest::StatusCode::OK {
-        let text = response.text()?;
-        log::debug!("response text {}", text);
-        return Err(ProcessingError::new(&format!("http error: {}", text)));
-    }
+        log::debug!("send headers {:?}", to_send.headers());
+        let response = client.execute(to_send)?;
+        log::debug!("response status {:?}", response.status());
+        log::debug!("response headers {:?}", response.headers());
+        log::debug!("response url {:?}", response.url());
+        let status = response.status();
+        if status != reqwest::StatusCode::OK {
+            let retry_after = retry::retry_after(response.headers());
+            let text = response.text()?;
+            log::debug!("response text {}", text);
+            return Err(retry::Failure {
+                error: ProcessingError::from_status(
+                    status,
+                    &format!("http error {}: {}", status, text),
+                ),
+                retry_after,
+            });
+        }
+        Ok(unpack(response)?)
+    })
+}
+
+/// Read the image, user data and units spent from a process API response
+fn unpack(
+    response: reqwest::blocking::Response,
+) -> std::result::Result<(String, Vec<u8>, Option<f64>), ProcessingError> {
+    let spent = response
+        .headers()
+        .get(UNITS_SPENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok());
 
     let content = Cursor::new(response.bytes()?);
     let mut archive = Archive::new(content);
@@ -158,115 +812,492 @@ fn download(
     // io::copy(&mut content, &mut file)?;
     // return Ok(output.to_path_buf());
     // Ok((meta, image))
-    Ok((meta, image))
+    Ok((meta, image, spent))
 }
 
-fn add_text(image: &[u8], text: &str) -> std::result::Result<Vec<u8>, ProcessingError> {
-    let mut tmp = ImageReader::new(Cursor::new(image))
-        .with_guessed_format()?
-        .decode()?;
+fn add_text(image: &mut RgbaImage, text: &str) -> std::result::Result<(), ProcessingError> {
     let red = Rgba([255u8, 0u8, 0u8, 127u8]);
     let scale = ab_glyph::PxScale { x: 10.0, y: 10.0 };
     let font = ab_glyph::FontRef::try_from_slice(include_bytes!("DejaVuSans.ttf"))?;
-    draw_text_mut(&mut tmp, red, 5, 5, scale, &font, text);
-    let mut cursor = Cursor::new(Vec::new());
-    tmp.write_to(&mut cursor, ImageFormat::Png)?;
-    Ok(cursor.into_inner())
+    draw_text_mut(image, red, 5, 5, scale, &font, text);
+    Ok(())
+}
+
+/// Data collection and scene filter of a process API request
+///
+/// Sentinel-1 has no cloud cover, is only ordered by date and is terrain
+/// corrected, which matters in the mountains where foreshortening is worst.
+fn data_source(
+    collection: &str,
+    start_time: &str,
+    end_time: &str,
+    options: &ImageryOptions,
+) -> process_api::DataSource {
+    let data = process_api::DataSource::new(collection, start_time, end_time);
+    if collection.starts_with("sentinel-1") {
+        data.with_radar_filter("IW", "DV", "HIGH")
+            .with_mosaicking_order(process_api::MosaickingOrder::MostRecent)
+            .with_processing(process_api::Processing::default().with_terrain_correction())
+    } else {
+        data.with_max_cloud_coverage(options.max_cloud_coverage)
+            .with_mosaicking_order(options.order.mosaicking_order())
+    }
 }
 
+/// Build a process API request for a web mercator `[min_x, min_y, max_x, max_y]`
+/// box rendered `size` pixels square
 fn format_request(
-    min_x: f64,
-    min_y: f64,
-    max_x: f64,
-    max_y: f64,
-    start_time: OffsetDateTime,
-    end_time: OffsetDateTime,
-    max_cloud_coverage: f64,
-) -> String {
+    bbox: [f64; 4],
+    size: u32,
+    (start_time, end_time): (OffsetDateTime, OffsetDateTime),
+    options: &ImageryOptions,
+    script: &evalscript::Evalscript,
+) -> process_api::ProcessRequest {
     let formatter =
         time::format_description::parse("[year]-[month]-[day]T[hour]:[minute]:[second]Z").unwrap();
-    return format!(
-        r#"{{
-    "input": {{
-        "bounds": {{
-           "properties": {{
-                "crs": "http://www.opengis.net/def/crs/EPSG/0/3857"
-            }},
-             "bbox": [
-                {min_x},
-                {min_y},
-                {max_x},
-                {max_y}
-            ]
-        }},
-        "data": [
-            {{
-                "type": "sentinel-2-l1c",
-                "dataFilter": {{
-                    "timeRange": {{
-                        "from": "{start_time}",
-                        "to": "{end_time}"
-                    }},
-                    "maxCloudCoverage": {max_cloud_coverage}
-                }}
-            }}
-        ]
-    }},
-    "output": {{
-        "width": 256,
-        "height": 256,
-        "responses": [
-            {{
-                "identifier": "default",
-                "format": {{
-                    "type": "image/png"
-                }}
-            }},
-            {{
-                "identifier": "userdata",
-                "format": {{
-                    "type": "application/json"
-                }}
-            }}
-        ]
-    }},
-    "evalscript": "{script}"
-}}"#,
-        start_time = start_time.format(&formatter).unwrap(),
-        end_time = end_time.format(&formatter).unwrap(),
-        script = IMAGE_SCRIPT.replace(r#"""#, r#"\""#).replace("\n", "\\n")
+    let data = data_source(
+        &script.collection,
+        &start_time.format(&formatter).unwrap(),
+        &end_time.format(&formatter).unwrap(),
+        options,
+    )
+    .with_resampling(
+        options.upsampling.or(script.upsampling),
+        options.downsampling.or(script.downsampling),
     );
+    process_api::ProcessRequest::new(bbox, size, size, &script.source).with_data(data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::approx;
+    use crate::standin;
     use std::path::Path;
-    use time::macros::datetime;
+    use time::macros::{date, datetime};
+
+    /// A process API response with a blank image and the given user data
+    fn process_reply(userdata: &str) -> standin::Reply {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(TILE_SIZE, TILE_SIZE))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in [("default.png", png), ("userdata.json", userdata.into())] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_slice())
+                .unwrap();
+        }
+        standin::Reply::bytes("application/tar", builder.into_inner().unwrap())
+    }
+
+    /// Compare a request body with the json stored in `src/snapshots`
+    fn assert_snapshot(request: &process_api::ProcessRequest, snapshot: &str) {
+        let body = serde_json::to_string_pretty(request).unwrap();
+        assert_eq!(body, snapshot.trim_end());
+    }
 
     #[test]
     fn test_format() {
-        println!(
-            "{}",
-            format_request(
-                1.0,
-                2.0,
-                3.0,
-                4.0,
+        let script = evalscript::Evalscript::new(
+            "test",
+            "//VERSION=3\n//COLLECTION=sentinel-2-l2a\nfunction setup() {}",
+        );
+        let options =
+            ImageryOptions::parse(Some(22.3), None, None, None, Some("least-cloudy")).unwrap();
+        let request = format_request(
+            [1.0, 2.0, 3.0, 4.0],
+            TILE_SIZE,
+            (
+                datetime!(2025-01-01 0:00 UTC),
+                datetime!(2025-02-08 0:00 UTC),
+            ),
+            &options,
+            &script,
+        );
+        assert_snapshot(&request, include_str!("snapshots/request_optical.json"));
+    }
+
+    #[test]
+    fn test_format_radar() {
+        let script = evalscript::Library::sar().get("vv").unwrap().clone();
+        let script = evalscript::Evalscript {
+            source: "//VERSION=3".to_string(),
+            ..script
+        };
+        let request = format_request(
+            [1.0, 2.0, 3.0, 4.0],
+            TILE_SIZE,
+            (
                 datetime!(2025-01-01 0:00 UTC),
                 datetime!(2025-02-08 0:00 UTC),
-                22.3
-            )
+            ),
+            &ImageryOptions::default(),
+            &script,
+        );
+        assert_snapshot(&request, include_str!("snapshots/request_radar.json"));
+    }
+
+    #[test]
+    fn test_imagery_options() {
+        let options =
+            ImageryOptions::parse(Some(10.0), None, None, Some("2025-02-01"), None).unwrap();
+        assert_eq!(options.dates, DateRange::Before(date!(2025 - 02 - 01)));
+        assert_eq!(
+            options.key(),
+            PathBuf::from("before-2025-02-01").join("cloud-10")
+        );
+        let (start, end) = options.time_range(OffsetDateTime::now_utc());
+        assert_eq!(start, datetime!(2025-01-02 0:00 UTC));
+        assert_eq!(end, datetime!(2025-02-01 0:00 UTC));
+
+        let options =
+            ImageryOptions::parse(None, Some("2025-01-01"), Some("2025-01-31"), None, None)
+                .unwrap();
+        assert_eq!(options.max_cloud_coverage, 30.0);
+        let (start, end) = options.time_range(OffsetDateTime::now_utc());
+        assert_eq!(start, datetime!(2025-01-01 0:00 UTC));
+        assert_eq!(end, datetime!(2025-02-01 0:00 UTC));
+        assert_eq!(
+            options.key(),
+            PathBuf::from("2025-01-01_2025-01-31").join("cloud-30")
+        );
+
+        assert_eq!(
+            ImageryOptions::parse(None, None, None, None, None).unwrap(),
+            ImageryOptions::default()
+        );
+        assert!(ImageryOptions::parse(Some(101.0), None, None, None, None).is_err());
+        assert!(ImageryOptions::parse(None, Some("2025-01-01"), None, None, None).is_err());
+        assert!(ImageryOptions::parse(None, None, None, Some("2025-02-30"), None).is_err());
+        assert!(
+            ImageryOptions::parse(None, Some("2025-02-01"), Some("2025-01-01"), None, None)
+                .is_err()
+        );
+        assert!(ImageryOptions::parse(
+            None,
+            Some("2025-01-01"),
+            Some("2025-01-31"),
+            Some("2025-02-01"),
+            None
+        )
+        .is_err());
+
+        let options =
+            ImageryOptions::parse(Some(10.0), None, None, None, Some("least-cloudy")).unwrap();
+        assert_eq!(options.order, SceneOrder::LeastCloudy);
+        assert_eq!(
+            options.key(),
+            PathBuf::from("latest")
+                .join("cloud-10")
+                .join("least-cloudy")
         );
-        assert!(false);
+        assert!(ImageryOptions::parse(None, None, None, None, Some("oldest")).is_err());
+
+        let options = ImageryOptions::default()
+            .with_resampling(Some("bicubic"), None)
+            .unwrap();
+        assert_eq!(
+            options.key(),
+            PathBuf::from("latest")
+                .join("cloud-30")
+                .join("upsample-bicubic")
+        );
+        assert!(ImageryOptions::default()
+            .with_resampling(None, Some("cubic"))
+            .is_err());
+
+        let options = ImageryOptions::default()
+            .with_source(Some("fallback"))
+            .unwrap();
+        assert_eq!(options.source, ImagerySource::SentinelThenLandsat);
+        assert_eq!(
+            options.key(),
+            PathBuf::from("latest")
+                .join("cloud-30")
+                .join("landsat-fallback")
+        );
+        assert_eq!(
+            ImageryOptions::default()
+                .with_source(Some("sentinel"))
+                .unwrap(),
+            ImageryOptions::default()
+        );
+        assert!(ImageryOptions::default()
+            .with_source(Some("modis"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_format_resampling() {
+        let script = evalscript::Evalscript::new(
+            "test",
+            "//VERSION=3\n//UPSAMPLING=BILINEAR\n//DOWNSAMPLING=BILINEAR\nfunction setup() {}",
+        );
+        let options = ImageryOptions::default()
+            .with_resampling(Some("bicubic"), None)
+            .unwrap();
+        let range = (
+            datetime!(2025-01-01 0:00 UTC),
+            datetime!(2025-02-08 0:00 UTC),
+        );
+        let request = format_request([1.0, 2.0, 3.0, 4.0], TILE_SIZE, range, &options, &script);
+        // the request's choice wins over the layer's
+        let processing = request.input.data[0].processing.clone().unwrap();
+        assert_eq!(
+            processing.upsampling,
+            Some(process_api::Resampling::Bicubic)
+        );
+        assert_eq!(
+            processing.downsampling,
+            Some(process_api::Resampling::Bilinear)
+        );
+    }
+
+    #[test]
+    fn test_scene_metadata() {
+        let userdata = r#"{"scenes": [
+            {"dateFrom": "2025-02-01T18:55:39Z", "dateTo": "2025-02-01T18:55:39Z", "tiles": [
+                {"tileOriginalId": "S2B_MSIL2A_20250201T185539_N0511_R113_T10UFU_20250201T211538",
+                 "cloudCoverage": 10.0},
+                {"tileOriginalId": "S2B_MSIL2A_20250201T185539_N0511_R113_T11UKP_20250201T211538",
+                 "cloudCoverage": 20.0}
+            ]},
+            {"dateFrom": "2025-01-29T19:05:41Z", "dateTo": "2025-01-29T19:05:41Z", "tiles": []}
+        ]}"#;
+        let metadata = SceneMetadata::from_userdata(userdata).unwrap();
+        assert_eq!(metadata.date, Some("2025-02-01".to_string()));
+        assert_eq!(metadata.scenes.len(), 2);
+        assert_eq!(metadata.scenes[0].orbit, Some(113));
+        assert_eq!(metadata.scenes[0].tiles.len(), 2);
+        assert_eq!(metadata.cloud_coverage(), Some(15.0));
+        assert_eq!(metadata.scenes[1].cloud_coverage, None);
+
+        let empty = SceneMetadata::from_userdata(r#"{"scenes": []}"#).unwrap();
+        assert_eq!(empty.date, None);
+        assert!(SceneMetadata::from_userdata("{}").is_err());
+
+        // metadata stored before the source was recorded
+        let mut stored = serde_json::to_value(&empty).unwrap();
+        assert!(stored.get("source").is_none());
+        let read: SceneMetadata = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(read.source, None);
+        stored["source"] = "landsat-ot-l2".into();
+        let read: SceneMetadata = serde_json::from_value(stored).unwrap();
+        assert_eq!(read.source, Some("landsat-ot-l2".to_string()));
+        assert_eq!(
+            relative_orbit("LC09_L2SP_045026_20250201_20250202_02_T1"),
+            None
+        );
+
+        let options = ImageryOptions::default().with_stamp(true);
+        assert_eq!(
+            options.key(),
+            PathBuf::from("latest").join("cloud-30").join("stamped")
+        );
+    }
+
+    #[test]
+    fn test_metatile() {
+        let metatile = Metatile::containing(12, 669, 1398, 4);
+        assert_eq!((metatile.x, metatile.y), (668, 1396));
+        let tiles = metatile.tiles();
+        assert_eq!(tiles.len(), 16);
+        assert_eq!(tiles[0], (668, 1396));
+        assert_eq!(tiles[15], (671, 1399));
+        assert!(tiles.contains(&(669, 1398)));
+        let [min_x, min_y, max_x, max_y] = metatile.bbox();
+        let [tile_min_x, _, _, tile_max_y] = Metatile::containing(12, 668, 1396, 1).bbox();
+        assert_eq!((min_x, max_y), (tile_min_x, tile_max_y));
+        approx::assert_approx!(max_x - min_x, 4.0 * 40_075_016.686 / 4096.0, 1e-3);
+        approx::assert_approx!(max_y - min_y, max_x - min_x, 1e-3);
     }
+
+    #[test]
+    fn test_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _) = mpsc::channel();
+        let job = MetatileJob {
+            cache_dir: dir.path().to_path_buf(),
+            cache_tx: tx,
+            process_url: URL.to_string(),
+            metatile: Metatile::containing(12, 669, 1397, 2),
+            script: evalscript::Library::builtin().get("ndvi").unwrap().clone(),
+            fallback: None,
+            options: ImageryOptions::default(),
+            token: "token".into(),
+            usage: Arc::new(usage::Usage::unlimited()),
+            limit: Arc::new(retry::Limit::new(1)),
+        };
+        let mut image = RgbaImage::new(2 * TILE_SIZE, 2 * TILE_SIZE);
+        image.put_pixel(TILE_SIZE + 1, TILE_SIZE + 2, Rgba([1, 2, 3, 255]));
+        let metadata = SceneMetadata::from_userdata(r#"{"scenes": []}"#).unwrap();
+        job.cut(&DynamicImage::ImageRgba8(image), &metadata, 669, 1397)
+            .unwrap();
+        let path = dir.path().join(job.tile_key(669, 1397));
+        let tile = ImageReader::open(&path)
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(tile.dimensions(), (TILE_SIZE, TILE_SIZE));
+        assert_eq!(*tile.get_pixel(1, 2), Rgba([1, 2, 3, 255]));
+        assert_eq!(read_metadata(&path), Some(metadata));
+        assert_eq!(
+            job.metatile_key(),
+            PathBuf::from("ndvi/latest/cloud-30/meta-2/12/668/1396.png")
+        );
+    }
+
+    #[test]
+    fn test_processing_units() {
+        assert_eq!(estimate_processing_units(512, 512, 3), 1.0);
+        let library = evalscript::Library::builtin();
+        assert_eq!(
+            tile_processing_units(library.get("true-color").unwrap()),
+            0.25
+        );
+        assert_eq!(
+            tile_processing_units(library.get("ndsi").unwrap()),
+            0.25 * 4.0 / 3.0
+        );
+        assert_eq!(estimate_processing_units(10, 10, 3), 0.01);
+    }
+
+    #[test]
+    fn test_fetch_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, server) = standin::serve(vec![
+            process_reply(r#"{"scenes": []}"#),
+            process_reply(
+                r#"{"scenes": [{"dateFrom": "2025-02-01T18:55:39Z", "dateTo": "2025-02-01T18:55:39Z",
+                    "tiles": [{"tileOriginalId": "LC09_L2SP_045026_20250201_20250202_02_T1"}]}]}"#,
+            ),
+        ]);
+        let (tx, _) = mpsc::channel();
+        let fallback = evalscript::Library::landsat()
+            .get(evalscript::DEFAULT)
+            .unwrap()
+            .clone();
+        let job = MetatileJob {
+            cache_dir: dir.path().to_path_buf(),
+            cache_tx: tx,
+            process_url: format!("{}/process", address),
+            metatile: Metatile::containing(12, 669, 1396, 1),
+            script: evalscript::Library::builtin()
+                .get(evalscript::DEFAULT)
+                .unwrap()
+                .clone(),
+            fallback: Some(fallback.clone()),
+            options: ImageryOptions::default()
+                .with_source(Some("fallback"))
+                .unwrap(),
+            token: "token".into(),
+            usage: Arc::new(usage::Usage::unlimited()),
+            limit: Arc::new(retry::Limit::new(1)),
+        };
+        let path = job.clone().fetch().unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received[1].body.contains(&fallback.collection));
+        let metadata = read_metadata(&path).unwrap();
+        assert_eq!(metadata.source, Some(fallback.collection.clone()));
+        assert_eq!(metadata.date, Some("2025-02-01".to_string()));
+        let tile = read_metadata(&dir.path().join(job.tile_key(669, 1396))).unwrap();
+        assert_eq!(tile, metadata);
+    }
+
+    #[test]
+    fn test_get_fallback_without_landsat_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, server) = standin::serve(vec![
+            standin::Reply::json(r#"{"access_token": "sentinel", "expires_in": 600}"#),
+            process_reply(r#"{"scenes": []}"#),
+        ]);
+        let sentinel = Sentinel::new(dir.path().to_path_buf())
+            .with_metatile_size(1)
+            .with_endpoints(
+                format!("{}/process", address),
+                token::Generator::new(format!("{}/token", address))
+                    .with_credentials("id", "secret"),
+            );
+        let options = ImageryOptions::default()
+            .with_source(Some("fallback"))
+            .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        // there is no Landsat snow classification, so no second request
+        let path = runtime
+            .block_on(sentinel.get(12, 669, 1396, "snow", &options))
+            .unwrap();
+        assert_eq!(server.join().unwrap().len(), 2);
+        let metadata = read_metadata(&path).unwrap();
+        assert_eq!(metadata.source, Some("sentinel-2-l2a".to_string()));
+    }
+
+    #[test]
+    fn test_get_refreshes_refused_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let token = |token: &str| {
+            standin::Reply::json(format!(
+                r#"{{"access_token": "{}", "expires_in": 600}}"#,
+                token
+            ))
+        };
+        let (address, server) = standin::serve(vec![
+            token("expired"),
+            standin::Reply::status(401, "token expired"),
+            token("renewed"),
+            process_reply(r#"{"scenes": []}"#),
+        ]);
+        let sentinel = Sentinel::new(dir.path().to_path_buf())
+            .with_metatile_size(1)
+            .with_endpoints(
+                format!("{}/process", address),
+                token::Generator::new(format!("{}/token", address))
+                    .with_credentials("id", "secret"),
+            );
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let path = runtime
+            .block_on(sentinel.get(
+                12,
+                669,
+                1396,
+                evalscript::DEFAULT,
+                &ImageryOptions::default(),
+            ))
+            .unwrap();
+        assert!(path.is_file());
+        let received = server.join().unwrap();
+        assert_eq!(received[1].header("authorization"), Some("Bearer expired"));
+        assert_eq!(received[3].header("authorization"), Some("Bearer renewed"));
+    }
+
     #[test]
     fn test_get() {
-        let path = Path::new("/tmp/sentinel_12_669_1396.png").to_path_buf();
+        let dir = Path::new("/tmp/sentinel").to_path_buf();
         let token_generator = token::Generator::new(TOKEN_URL);
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let token = runtime.block_on(token_generator.get()).unwrap();
-        let _ = generate_tile(path.clone(), 12, 669, 1396, token).unwrap();
+        let library = evalscript::Library::builtin();
+        let (tx, _) = mpsc::channel();
+        let job = MetatileJob {
+            cache_dir: dir.clone(),
+            cache_tx: tx,
+            process_url: URL.to_string(),
+            metatile: Metatile::containing(12, 669, 1396, 1),
+            script: library.get(evalscript::DEFAULT).unwrap().clone(),
+            fallback: None,
+            options: ImageryOptions::default(),
+            token,
+            usage: Arc::new(usage::Usage::unlimited()),
+            limit: Arc::new(retry::Limit::new(1)),
+        };
+        let path = job.clone().fetch().unwrap();
         assert!(path.exists());
+        assert!(dir.join(job.tile_key(669, 1396)).exists());
     }
 }
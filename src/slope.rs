@@ -1,14 +1,54 @@
 use crate::cache;
+use crate::elevation;
 use crate::processing::{ProcessingError, ProcessingResult};
+use crate::regional;
 use crate::tile;
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::mpsc;
-use tempfile::NamedTempFile;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Description of a generated tile, stored next to it in the cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileMetadata {
+    /// true if any elevation under the tile had voids filled
+    pub interpolated: bool,
+    /// name of the elevation source, or `regional` if a regional DEM covers the tile
+    #[serde(default)]
+    pub source: String,
+    /// elevation cells and regional DEM files the tile was built from
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// finest pixel size of the elevation in meters
+    #[serde(default)]
+    pub resolution: f64,
+    /// mean over the source cells of the fraction of each whole cell which
+    /// was void, including parts of the cells outside the tile
+    #[serde(default, alias = "void_fraction")]
+    pub cell_void_fraction: f64,
+    /// RFC 3339 time the tile was made
+    #[serde(default)]
+    pub generated: String,
+}
+
+/// Path of the metadata stored alongside a tile
+pub fn metadata_path(tile: &Path) -> PathBuf {
+    tile.with_extension("json")
+}
+
+pub fn read_metadata(tile: &Path) -> Option<TileMetadata> {
+    let text = fs::read_to_string(metadata_path(tile)).ok()?;
+    serde_json::from_str(&text).ok()
+}
 
 pub struct Pipeline {
+    elevation: Arc<elevation::Elevation>,
+    regional: regional::Registry,
     cache_dir: PathBuf,
     cache_tx: mpsc::Sender<(
         cache::Request,
@@ -18,7 +58,7 @@ pub struct Pipeline {
 }
 
 impl Pipeline {
-    pub fn new(cache_dir: PathBuf) -> Self {
+    pub fn new(cache_dir: PathBuf, elevation: Arc<elevation::Elevation>) -> Self {
         let cache = cache::Cache::from_existing_directory(
             cache_dir.clone(),
             10_000_000_000,
@@ -28,44 +68,144 @@ impl Pipeline {
         .unwrap();
         let cache_tx = cache::run_cache(cache);
         Pipeline {
+            elevation,
+            regional: regional::Registry::default(),
             cache_dir,
             cache_tx,
             process_lock: tokio::sync::Mutex::new(0),
         }
     }
 
-    pub async fn get(&self, elevations: Vec<PathBuf>, zoom: u8, x: u32, y: u32) -> Result<PathBuf> {
+    /// Prefer high resolution regional elevation where it covers a tile
+    pub fn with_regional(mut self, regional: regional::Registry) -> Self {
+        self.regional = regional;
+        self
+    }
+
+    /// true if the tile can be made, zooms past `tile::MAX_ZOOM` need regional elevation
+    pub fn serves(&self, zoom: u8, x: u32, y: u32) -> bool {
+        if zoom < tile::MIN_ZOOM {
+            return false;
+        }
+        if zoom <= tile::MAX_ZOOM {
+            return true;
+        }
+        let extent = elevation::CellBounds::from_tile(zoom, x, y);
+        self.regional
+            .max_zoom(&extent)
+            .is_some_and(|max_zoom| zoom <= max_zoom)
+    }
+
+    pub async fn get(&self, zoom: u8, x: u32, y: u32) -> Result<PathBuf> {
+        let extent = elevation::CellBounds::from_tile(zoom, x, y);
+        let mut dems = self.regional.covering(&extent);
+        log::debug!("regional elevation {:?}", dems);
+        let base = match dems.iter().position(|dem| dem.contains(&extent)) {
+            Some(index) => {
+                // coarser rasters would be hidden under this one
+                let base = dems.remove(index);
+                dems.truncate(index);
+                Some(base)
+            }
+            None => None,
+        };
+        // a raster's extent may cover the tile while its data does not, so
+        // the elevation source always fills in under it
+        let bounds = tile::tile_bounds(zoom, x, y);
+        log::debug!("tile bounds {:?}", bounds);
+        let cells = self.elevation.get_all(bounds).await?;
+        log::debug!("elevations {:?}", cells);
+        let source = self.elevation.source();
         let key = PathBuf::new()
             .join(format!("{}", zoom))
             .join(format!("{}", x))
             .join(format!("{}.png", y));
         let output = self.cache_dir.join(&key);
-        let generator = move || process(output, elevations, zoom, x, y);
-        let (tx, rx) = mpsc::channel();
-        self.cache_tx
-            .send((cache::Request { key, send_back: tx }, Box::new(generator)))
-            .unwrap();
-        let result = rx.recv()??;
-        return Ok(result);
+        let sources = TileSources {
+            base,
+            cells,
+            dems,
+            void_value: source.void_value(),
+            source_name: source.name().to_string(),
+            source_resolution: source.resolution(),
+        };
+        let generator = move || process(output, sources, zoom, x, y);
+        cache::ask(&self.cache_tx, key, generator).await
     }
 }
 
-fn process(
-    output: PathBuf,
-    elevations: Vec<PathBuf>,
-    zoom: u8,
-    x: u32,
-    y: u32,
-) -> cache::CacheResult {
+/// Elevation making up a tile
+struct TileSources {
+    /// regional raster whose extent covers the whole tile, laid over the
+    /// cells which fill any gaps in its data
+    base: Option<regional::RegionalDem>,
+    cells: Vec<elevation::Cell>,
+    /// regional rasters laid over the base or cells, finest first
+    dems: Vec<regional::RegionalDem>,
+    void_value: f64,
+    source_name: String,
+    source_resolution: f64,
+}
+
+impl TileSources {
+    fn metadata(&self) -> TileMetadata {
+        let file_name = |dem: &regional::RegionalDem| {
+            dem.path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let mut sources: Vec<String> = self.cells.iter().map(|cell| cell.id.clone()).collect();
+        sources.extend(self.base.iter().chain(self.dems.iter()).map(file_name));
+        let mut resolution = match &self.base {
+            Some(base) => base.resolution,
+            None => self.source_resolution,
+        };
+        for dem in &self.dems {
+            resolution = resolution.min(dem.resolution);
+        }
+        let cell_void_fraction = if self.cells.is_empty() {
+            0.0
+        } else {
+            self.cells
+                .iter()
+                .map(|cell| cell.void_fraction)
+                .sum::<f64>()
+                / self.cells.len() as f64
+        };
+        TileMetadata {
+            interpolated: self.cells.iter().any(|cell| cell.interpolated),
+            source: match self.base {
+                Some(_) => "regional".to_string(),
+                None => self.source_name.clone(),
+            },
+            sources,
+            resolution: (resolution * 10.0).round() / 10.0,
+            cell_void_fraction,
+            generated: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn process(output: PathBuf, sources: TileSources, zoom: u8, x: u32, y: u32) -> cache::CacheResult {
     let parent = output.parent().expect("output should have parent dir");
     if !parent.exists() {
         fs::create_dir_all(parent)?;
     }
     log::info!("make shaded slope tile {} {} {}", zoom, x, y);
-    let vrt_file = NamedTempFile::new()?;
-    let vrt_path = vrt_file.path().to_path_buf();
-    make_vrt(&elevations, &vrt_path)?;
-    let elevation_tile = tile::single_tile(vrt_path, zoom, x as f64, y as f64).unwrap();
+    // intermediate rasters are written next to the VRT, so keep them out of
+    // the elevation directories and remove them once the tile is made
+    let work = tempfile::tempdir()?;
+    let vrt_path = work.path().join("elevation.vrt");
+    let elevations: Vec<PathBuf> = sources.cells.iter().map(|cell| cell.path.clone()).collect();
+    make_vrt(&elevations, sources.void_value, &vrt_path)?;
+    let elevation_tile = tile::single_tile(vrt_path, zoom, x as f64, y as f64)?;
+    // coarsest first so finer rasters are laid over it
+    for dem in sources.base.iter().chain(sources.dems.iter().rev()) {
+        regional::overlay(dem, &elevation_tile)?;
+    }
     log::debug!("have elevation tile {:?}", elevation_tile);
     // scale slope by cosine of tile center latitude since this is a conformal projection
     // from John P. Snyder https://doi.org/10.3133/pp1395
@@ -76,6 +216,10 @@ fn process(
     log::debug!("have slope tile {:?}", slope);
     angle_shade(&slope, &output)?;
     log::debug!("have shaded tile {:?}", output);
+    fs::write(
+        metadata_path(&output),
+        serde_json::to_string(&sources.metadata())?,
+    )?;
     if output.exists() {
         log::info!("return generated slope tile {:?}", output);
         return Ok(output);
@@ -124,6 +268,7 @@ pub fn angle_shade(input: &PathBuf, output: &PathBuf) -> ProcessingResult<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::approx;
 
     #[test]
     fn test_slope() {
@@ -144,6 +289,48 @@ mod tests {
         assert!(output.exists());
     }
 
+    #[test]
+    fn test_metadata() {
+        let cell = |id: &str, void_fraction: f64| elevation::Cell {
+            id: id.into(),
+            path: PathBuf::from(id),
+            void_fraction,
+            interpolated: void_fraction > 0.0,
+        };
+        let sources = TileSources {
+            base: None,
+            cells: vec![cell("N49W120.hgt", 0.06), cell("N49W119.hgt", 0.0)],
+            dems: vec![regional::RegionalDem {
+                path: PathBuf::from("/data/lidar.tif"),
+                bounds: elevation::CellBounds::from_tile(12, 669, 1396),
+                resolution: 1.04,
+            }],
+            void_value: -32768.0,
+            source_name: "srtm".into(),
+            source_resolution: 30.0,
+        };
+        let metadata = sources.metadata();
+        assert_eq!(
+            metadata.sources,
+            vec!["N49W120.hgt", "N49W119.hgt", "lidar.tif"]
+        );
+        assert_eq!(metadata.resolution, 1.0);
+        assert!(metadata.interpolated);
+        approx::assert_approx!(metadata.cell_void_fraction, 0.03, 1e-9);
+        assert!(OffsetDateTime::parse(&metadata.generated, &Rfc3339).is_ok());
+
+        let metadata = TileSources {
+            dems: Vec::new(),
+            ..sources
+        }
+        .metadata();
+        assert_eq!(metadata.source, "srtm");
+        assert_eq!(metadata.resolution, 30.0);
+        // metadata written before these fields existed still reads
+        let old: TileMetadata = serde_json::from_str(r#"{"interpolated":false}"#).unwrap();
+        assert!(old.sources.is_empty());
+    }
+
     #[test]
     fn test_cosine_approximation() {
         // We approximate corrections to slope numbers with the center latitude
@@ -175,8 +362,13 @@ mod tests {
     }
 }
 
-fn make_vrt(paths: &[PathBuf], output: &PathBuf) -> ProcessingResult<()> {
+fn make_vrt(paths: &[PathBuf], void_value: f64, output: &PathBuf) -> ProcessingResult<()> {
+    // sea level fill cells may not match the data type of the source
     let result = process::Command::new("gdalbuildvrt")
+        .arg("-ot")
+        .arg("Float32")
+        .arg("-srcnodata")
+        .arg(format!("{}", void_value))
         .arg(&output)
         .args(paths)
         .output()?;
@@ -0,0 +1,154 @@
+use crate::cache;
+use crate::processing::ProcessingResult;
+use crate::sentinel;
+use crate::slope;
+use anyhow::Result;
+use image::{ImageReader, Rgba, RgbaImage};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Evalscript classifying snow, see `evalscripts/snow.js`
+pub const SCRIPT: &str = "snow";
+
+// colours the snow script uses for each class
+const SNOW: Rgba<u8> = Rgba([0, 153, 255, 204]);
+const CLOUD: Rgba<u8> = Rgba([128, 128, 128, 128]);
+const NO_DATA: Rgba<u8> = Rgba([0, 0, 0, 96]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnowClass {
+    Snow,
+    NoSnow,
+    Cloud,
+    NoData,
+}
+
+/// Read the class of a pixel of the snow layer
+pub fn classify(pixel: &Rgba<u8>) -> SnowClass {
+    match *pixel {
+        SNOW => SnowClass::Snow,
+        CLOUD => SnowClass::Cloud,
+        NO_DATA => SnowClass::NoData,
+        _ => SnowClass::NoSnow,
+    }
+}
+
+/// Keep the slope shading only where the ground is steep and snow covered
+///
+/// Cloud over steep ground is shown grey since its snow cover is unknown.
+pub fn composite(slope: &Path, snow: &Path, output: &Path) -> ProcessingResult<()> {
+    let slope = ImageReader::open(slope)?.decode()?.to_rgba8();
+    let snow = ImageReader::open(snow)?.decode()?.to_rgba8();
+    let mut result = RgbaImage::new(slope.width(), slope.height());
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        let shade = slope.get_pixel(x, y);
+        // shallow slopes are transparent in the shaded tile
+        if shade[3] == 0 || x >= snow.width() || y >= snow.height() {
+            continue;
+        }
+        *pixel = match classify(snow.get_pixel(x, y)) {
+            SnowClass::Snow => *shade,
+            SnowClass::Cloud => CLOUD,
+            SnowClass::NoSnow | SnowClass::NoData => Rgba([0, 0, 0, 0]),
+        };
+    }
+    result.save_with_format(output, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// Tiles of steep, snow covered ground from the slope and snow layers
+pub struct SteepSnow {
+    cache_dir: PathBuf,
+    cache_tx: mpsc::Sender<(
+        cache::Request,
+        Box<dyn FnOnce() -> cache::CacheResult + Send>,
+    )>,
+}
+
+impl SteepSnow {
+    pub fn new(cache_dir: PathBuf) -> Self {
+        // snow changes as often as the imagery so expire on the same schedule
+        let cache = cache::Cache::from_existing_directory(
+            cache_dir.clone(),
+            10_000_000_000,
+            100_000_000,
+            86400,
+        )
+        .unwrap();
+        SteepSnow {
+            cache_dir,
+            cache_tx: cache::run_cache(cache),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        slope: &slope::Pipeline,
+        sentinel: &sentinel::Sentinel,
+        zoom: u8,
+        x: u32,
+        y: u32,
+        options: &sentinel::ImageryOptions,
+    ) -> Result<PathBuf> {
+        let key = options
+            .key()
+            .join(format!("{}", zoom))
+            .join(format!("{}", x))
+            .join(format!("{}.png", y));
+        let output = self.cache_dir.join(&key);
+        let slope_tile = slope.get(zoom, x, y).await?;
+        let snow_tile = sentinel.get(zoom, x, y, SCRIPT, options).await?;
+        let generator = move || {
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            composite(&slope_tile, &snow_tile, &output)?;
+            Ok(output)
+        };
+        cache::ask(&self.cache_tx, key, generator).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_composite() {
+        let dir = tempfile::tempdir().unwrap();
+        let steep = Rgba([235, 51, 35, 255]);
+        let mut slope = RgbaImage::new(4, 1);
+        for x in 0..3 {
+            slope.put_pixel(x, 0, steep);
+        }
+        let mut snow = RgbaImage::new(4, 1);
+        snow.put_pixel(0, 0, SNOW);
+        snow.put_pixel(1, 0, CLOUD);
+        snow.put_pixel(3, 0, SNOW);
+        let slope_path = dir.path().join("slope.png");
+        let snow_path = dir.path().join("snow.png");
+        let output = dir.path().join("steep_snow.png");
+        slope.save(&slope_path).unwrap();
+        snow.save(&snow_path).unwrap();
+
+        composite(&slope_path, &snow_path, &output).unwrap();
+        let result = ImageReader::open(&output)
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(*result.get_pixel(0, 0), steep);
+        assert_eq!(*result.get_pixel(1, 0), CLOUD);
+        // steep without snow and snow on shallow ground are both clear
+        assert_eq!(result.get_pixel(2, 0)[3], 0);
+        assert_eq!(result.get_pixel(3, 0)[3], 0);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&SNOW), SnowClass::Snow);
+        assert_eq!(classify(&NO_DATA), SnowClass::NoData);
+        assert_eq!(classify(&Rgba([0, 0, 0, 0])), SnowClass::NoSnow);
+    }
+}
@@ -1,24 +1,37 @@
 use crate::cache;
+use crate::earthdata;
+use crate::elevation;
+use crate::processing;
 use crate::tile;
-use anyhow::Result;
-use std::env;
 use std::fs;
 use std::io;
-use std::io::Cursor;
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::thread;
 use std::time;
-use tokio;
+use tempfile::NamedTempFile;
 extern crate reqwest;
 
+/// SRTM marks voids in the height files with the minimum 16 bit integer
+pub const VOID_VALUE: f64 = -32768.0;
+
+/// sizes in bytes of one and three arc second height files
+pub const HGT_SIZES: [u64; 2] = [3601 * 3601 * 2, 1201 * 1201 * 2];
+
+const ATTEMPTS: u32 = 5;
+
 // const URL: &'static str = "https://srtm.csi.cgiar.org/wp-content/uploads/files/srtm_5x5/TIFF/srtm_{i_lon:02d}_{i_lat:02d}.zip";
 
+const URL: &str = "https://e4ftl01.cr.usgs.gov/MEASURES/SRTMGL1.003/2000.02.11";
+
+/// Follow earthdata redirects, requesting bytes from `offset` onward if not zero
 fn redirect_with_auth_and_cookies(
     url: &str,
-) -> std::result::Result<reqwest::blocking::Response, cache::GeneratorError> {
+    credentials: &earthdata::Credentials,
+    offset: u64,
+) -> std::result::Result<reqwest::blocking::Response, earthdata::LoginError> {
     log::debug!("retrieving {}", url);
-    let password = env::var("FLYTILE_SRTM_PASSWORD")?;
     let mut jar = Some(reqwest::cookie::Jar::default());
     let mut new_url = url.to_string();
     for _i in 0..10 {
@@ -27,153 +40,299 @@ fn redirect_with_auth_and_cookies(
             .redirect(reqwest::redirect::Policy::none())
             .timeout(time::Duration::from_secs(180))
             .build()?;
-        let response = client
-            .get(&new_url)
-            .basic_auth("dustin.lagoy", Some(&password))
-            .send()?;
+        let mut request = credentials.authorize(client.get(&new_url));
+        if offset > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send()?;
         log::debug!("response status {:?}", response.status());
         log::debug!("response headers {:?}", response.headers());
         log::debug!("response url {:?}", response.url());
-        if response.status() == reqwest::StatusCode::OK {
+        if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(response);
+        }
+        if response.status() == reqwest::StatusCode::OK
+            || response.status() == reqwest::StatusCode::PARTIAL_CONTENT
+        {
+            if is_html(&response) {
+                // earthdata serves a page instead of data when the account
+                // still needs to agree to something
+                return Err(earthdata::LoginError::EulaNotAccepted(
+                    response.url().to_string(),
+                ));
+            }
             return Ok(response);
         }
+        if response.status() == reqwest::StatusCode::FORBIDDEN {
+            return Err(earthdata::LoginError::EulaNotAccepted(
+                response.url().to_string(),
+            ));
+        }
         let response = response.error_for_status()?;
         new_url = response
             .headers()
             .get(reqwest::header::LOCATION)
-            .expect("response did not contain a location")
-            .to_str()?
+            .ok_or(earthdata::LoginError::Http(
+                "redirect did not contain a location".into(),
+            ))?
+            .to_str()
+            .map_err(|_| earthdata::LoginError::Http("invalid location".into()))?
             .to_string();
+        if earthdata::is_eula_redirect(&new_url) {
+            return Err(earthdata::LoginError::EulaNotAccepted(new_url));
+        }
         let tmp_jar = reqwest::cookie::Jar::default();
         for cookie in response.headers().get_all(reqwest::header::SET_COOKIE) {
-            let cookie_url = response.url();
-            log::debug!("add cookie {} for {}", cookie.to_str()?, &cookie_url);
-            tmp_jar.add_cookie_str(cookie.to_str()?, &cookie_url);
+            if let Ok(cookie) = cookie.to_str() {
+                let cookie_url = response.url();
+                log::debug!("add cookie {} for {}", cookie, &cookie_url);
+                tmp_jar.add_cookie_str(cookie, cookie_url);
+            }
         }
         jar = Some(tmp_jar);
     }
-    return Err(cache::GeneratorError::new("too many redirects"));
+    Err(earthdata::LoginError::Http("too many redirects".into()))
+}
+
+fn is_html(response: &reqwest::blocking::Response) -> bool {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"))
+}
+
+/// Log in to Earthdata and start a download without reading it
+pub fn check_credentials(
+    credentials: &earthdata::Credentials,
+) -> std::result::Result<(), earthdata::LoginError> {
+    let url = format!("{}/N49W120.SRTMGL1.hgt.zip", URL);
+    redirect_with_auth_and_cookies(&url, credentials, 0)?;
+    Ok(())
 }
 
+/// Elevation cells from the NASA SRTMGL1 dataset at one arc second
 pub struct SRTM {
-    cache_dir: PathBuf,
-    cache_tx: mpsc::Sender<(
-        cache::Request,
-        Box<dyn FnOnce() -> cache::CacheResult + Send>,
-    )>,
-    download_lock: tokio::sync::Mutex<u8>,
+    credentials: Option<earthdata::Credentials>,
 }
 
 impl SRTM {
-    pub fn new(cache_dir: PathBuf) -> Self {
-        let cache = cache::Cache::from_existing_directory(
-            cache_dir.clone(),
-            10_000_000_000,
-            100_000_000,
-            86400 * 365,
-        )
-        .unwrap();
-        let tx = cache::run_cache(cache);
+    pub fn new(credentials: earthdata::Credentials) -> Self {
         SRTM {
-            cache_dir,
-            cache_tx: tx,
-            download_lock: tokio::sync::Mutex::new(0),
+            credentials: Some(credentials),
         }
     }
 
-    pub async fn get(&self, point: tile::GeoPoint) -> Result<PathBuf> {
-        let id = srtm_id(&point);
-        log::debug!("get srtm {} for point {:?}", id, point);
-        self.get_tile(&id).await
-    }
-
-    pub async fn get_tile(&self, id: &str) -> Result<PathBuf> {
-        let out_dir = self.cache_dir.clone();
-        let tmp: String = id.to_string();
-        let generator = move || download_tile(out_dir, &tmp);
-        let (tx, rx) = mpsc::channel();
-        self.cache_tx
-            .send((
-                cache::Request {
-                    key: id.into(),
-                    send_back: tx,
-                },
-                Box::new(generator),
-            ))
-            .unwrap();
-        let result = rx.recv()??;
-        return Ok(result);
-    }
-
-    pub async fn get_all(&self, bounds: tile::Bounds) -> Result<Vec<PathBuf>> {
-        let min_lon = bounds
-            .north_west
-            .longitude
-            .min(bounds.south_west.longitude)
-            .floor() as i32;
-        let min_lat = bounds
-            .south_west
-            .latitude
-            .min(bounds.south_east.latitude)
-            .floor() as i32;
-        let max_lon = bounds
-            .north_east
-            .longitude
-            .max(bounds.south_east.longitude)
-            .ceil() as i32;
-        let max_lat = bounds
-            .north_west
-            .latitude
-            .max(bounds.north_east.latitude)
-            .ceil() as i32;
-        let mut files = vec![];
-        log::debug!("lon bounds {} {}", min_lon, max_lon);
-        log::debug!("lat bounds {} {}", min_lat, max_lat);
-        for i in min_lon..max_lon {
-            for j in min_lat..max_lat {
-                let point = tile::GeoPoint {
-                    longitude: i as f64,
-                    latitude: j as f64,
-                };
-                files.push(self.get(point).await?);
+    /// Use credentials from the environment, downloads fail if there are none
+    pub fn from_env() -> Self {
+        let credentials = match earthdata::Credentials::from_env() {
+            Ok(credentials) => Some(credentials),
+            Err(error) => {
+                log::warn!("srtm downloads will fail: {}", error);
+                None
             }
-        }
-        return Ok(files);
+        };
+        SRTM { credentials }
+    }
+}
+
+impl elevation::ElevationSource for SRTM {
+    fn name(&self) -> &str {
+        "srtm"
+    }
+
+    fn cell_id(&self, point: &tile::GeoPoint) -> String {
+        srtm_id(point)
+    }
+
+    fn cell_bounds(&self, id: &str) -> Option<elevation::CellBounds> {
+        srtm_bounds(id)
+    }
+
+    fn void_value(&self) -> f64 {
+        VOID_VALUE
+    }
+
+    fn fetch_cell(&self, output_directory: &Path, id: &str) -> cache::CacheResult {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or(earthdata::LoginError::MissingCredentials)?;
+        download_tile(output_directory.to_path_buf(), id, credentials)
     }
 }
 
-fn download_tile(output_directory: PathBuf, id: &str) -> cache::CacheResult {
+/// Download a cell, resuming and retrying on failure
+fn download_tile(
+    output_directory: PathBuf,
+    id: &str,
+    credentials: &earthdata::Credentials,
+) -> cache::CacheResult {
     let url = format!(
-        "https://e4ftl01.cr.usgs.gov/MEASURES/SRTMGL1.003/2000.02.11/{}.SRTMGL1.hgt.zip",
-        id.strip_suffix(".hgt")
-            .expect("key did not contain .hgt suffix")
+        "{}/{}.SRTMGL1.hgt.zip",
+        URL,
+        id.strip_suffix(".hgt").ok_or(cache::GeneratorError::new(
+            "key did not contain .hgt suffix"
+        ))?
     );
-    let zipfile = Path::new("/tmp").join(format!("{}.zip", id));
-    log::info!("downloading srtm image {}", id);
-    let response = redirect_with_auth_and_cookies(&url)?;
-    response.error_for_status_ref()?;
-    let mut content = Cursor::new(response.bytes()?);
-    let mut file = fs::File::create(&zipfile)?;
-    io::copy(&mut content, &mut file)?;
-    let mut outputs = extract(output_directory, zipfile);
-    return outputs
-        .pop()
-        .ok_or(cache::GeneratorError::new("no outputs"));
-}
-
-fn extract(output_directory: PathBuf, path: PathBuf) -> Vec<PathBuf> {
-    let file = fs::File::open(&path).unwrap();
-    let mut archive = zip::ZipArchive::new(file).unwrap();
-    let mut outputs = Vec::new();
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        let outpath = Path::new(&output_directory).join(file.enclosed_name().unwrap());
-        log::debug!("extracting {:?}", outpath);
-        let mut outfile = fs::File::create(&outpath).unwrap();
-        io::copy(&mut file, &mut outfile).unwrap();
-        outputs.push(outpath);
-    }
-    outputs
+    // hidden so the cache does not pick it up as an item
+    let partial = output_directory.join(format!(".{}.zip.part", id));
+    let mut attempt = 0;
+    loop {
+        log::info!("downloading srtm image {} (attempt {})", id, attempt + 1);
+        let result = download_to(&url, &partial, credentials).and_then(|_| {
+            extract(&output_directory, &partial, id).inspect_err(|_| {
+                // a corrupt archive can not be resumed so start over
+                let _ = fs::remove_file(&partial);
+            })
+        });
+        match result {
+            Ok(output) => {
+                fs::remove_file(&partial)?;
+                return Ok(output);
+            }
+            Err(DownloadError::Login(error)) if !is_retryable(&error) => return Err(error.into()),
+            Err(error) => {
+                attempt += 1;
+                if attempt >= ATTEMPTS {
+                    return Err(error.into());
+                }
+                let delay = time::Duration::from_secs(2u64.pow(attempt));
+                log::warn!("download of {} failed, retry in {:?}: {}", id, delay, error);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+fn is_retryable(error: &earthdata::LoginError) -> bool {
+    matches!(
+        error,
+        earthdata::LoginError::Network(_) | earthdata::LoginError::Http(_)
+    )
+}
+
+#[derive(Debug)]
+enum DownloadError {
+    Login(earthdata::LoginError),
+    /// the connection failed or ended early while streaming the archive
+    Transfer(String),
+    Other(cache::GeneratorError),
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DownloadError::Login(error) => write!(f, "{}", error),
+            DownloadError::Transfer(message) => write!(f, "download failed: {}", message),
+            DownloadError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl From<earthdata::LoginError> for DownloadError {
+    fn from(error: earthdata::LoginError) -> Self {
+        DownloadError::Login(error)
+    }
+}
+impl From<cache::GeneratorError> for DownloadError {
+    fn from(error: cache::GeneratorError) -> Self {
+        DownloadError::Other(error)
+    }
+}
+impl From<io::Error> for DownloadError {
+    fn from(error: io::Error) -> Self {
+        DownloadError::Other(error.into())
+    }
+}
+impl From<zip::result::ZipError> for DownloadError {
+    fn from(error: zip::result::ZipError) -> Self {
+        DownloadError::Other(error.into())
+    }
+}
+impl From<tempfile::PersistError> for DownloadError {
+    fn from(error: tempfile::PersistError) -> Self {
+        DownloadError::Other(error.into())
+    }
+}
+impl From<DownloadError> for cache::GeneratorError {
+    fn from(error: DownloadError) -> Self {
+        match error {
+            DownloadError::Login(error) => error.into(),
+            DownloadError::Transfer(_) => {
+                processing::ProcessingError::retryable(&format!("{}", error)).into()
+            }
+            DownloadError::Other(error) => error,
+        }
+    }
+}
+
+/// Stream a url to a file, continuing from where a previous attempt stopped
+fn download_to(
+    url: &str,
+    output: &Path,
+    credentials: &earthdata::Credentials,
+) -> std::result::Result<(), DownloadError> {
+    let offset = fs::metadata(output).map_or(0, |metadata| metadata.len());
+    if offset > 0 {
+        log::info!("resuming download of {} from byte {}", url, offset);
+    }
+    let mut response = redirect_with_auth_and_cookies(url, credentials, offset)?;
+    let mut file = match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => fs::OpenOptions::new().append(true).open(output)?,
+        // the previous attempt already has everything
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE => return Ok(()),
+        _ => fs::File::create(output)?,
+    };
+    let expected = response.content_length();
+    let copied = io::copy(&mut response, &mut file)
+        .map_err(|error| DownloadError::Transfer(format!("{}", error)))?;
+    if let Some(expected) = expected {
+        if copied != expected {
+            return Err(DownloadError::Transfer(format!(
+                "download ended after {} of {} bytes",
+                copied, expected
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Extract the height file from a downloaded archive into the output directory
+///
+/// The zip reader checks the CRC of the entry as it is read and the size must
+/// match a one or three arc second cell. The file is only moved into place once
+/// both checks pass.
+fn extract(
+    output_directory: &Path,
+    path: &Path,
+    id: &str,
+) -> std::result::Result<PathBuf, DownloadError> {
+    Ok(extract_entry(output_directory, path, id, id)?)
+}
+
+/// Extract the height file named `entry` from a zip archive into the output
+/// directory as `id`, with the same checks as a downloaded archive
+pub fn extract_entry(
+    output_directory: &Path,
+    path: &Path,
+    entry: &str,
+    id: &str,
+) -> cache::CacheResult {
+    let file = fs::File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut entry = archive.by_name(entry)?;
+    let mut temporary = NamedTempFile::new_in(output_directory)?;
+    log::debug!("extracting {} from {:?}", id, path);
+    let bytes = io::copy(&mut entry, &mut temporary)?;
+    if !HGT_SIZES.contains(&bytes) {
+        return Err(cache::GeneratorError::new(&format!(
+            "extracted {} has unexpected size {}",
+            id, bytes
+        )));
+    }
+    let output = output_directory.join(id);
+    temporary.persist(&output)?;
+    Ok(output)
 }
 
 pub fn srtm_id(point: &tile::GeoPoint) -> String {
@@ -192,6 +351,53 @@ pub fn srtm_id(point: &tile::GeoPoint) -> String {
     output
 }
 
+/// Count void samples in a height file, returning (voids, total samples)
+pub fn count_voids(path: &Path) -> io::Result<(u64, u64)> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut sample = [0u8; 2];
+    let mut voids = 0;
+    let mut total = 0;
+    loop {
+        match reader.read_exact(&mut sample) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+        // height files are big endian 16 bit integers
+        if i16::from_be_bytes(sample) as f64 == VOID_VALUE {
+            voids += 1;
+        }
+        total += 1;
+    }
+    Ok((voids, total))
+}
+
+/// Parse the extent of a cell from an id like `N44W122.hgt`
+pub fn srtm_bounds(id: &str) -> Option<elevation::CellBounds> {
+    let stem = id.split('.').next()?;
+    if stem.len() != 7 || !stem.is_ascii() {
+        return None;
+    }
+    let latitude: f64 = stem[1..3].parse().ok()?;
+    let longitude: f64 = stem[4..7].parse().ok()?;
+    let latitude = match &stem[0..1] {
+        "N" => latitude,
+        "S" => -latitude,
+        _ => return None,
+    };
+    let longitude = match &stem[3..4] {
+        "E" => longitude,
+        "W" => -longitude,
+        _ => return None,
+    };
+    Some(elevation::CellBounds {
+        min_longitude: longitude,
+        min_latitude: latitude,
+        max_longitude: longitude + 1.0,
+        max_latitude: latitude + 1.0,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -210,13 +416,104 @@ mod tests {
         assert_eq!(id, "S05E022");
     }
 
+    #[test]
+    fn test_bounds() {
+        let bounds = srtm_bounds("N44W122.hgt").unwrap();
+        assert_eq!(bounds.min_longitude, -122.0);
+        assert_eq!(bounds.min_latitude, 44.0);
+        assert_eq!(bounds.max_longitude, -121.0);
+        assert_eq!(bounds.max_latitude, 45.0);
+        let bounds = srtm_bounds("S05E022.hgt").unwrap();
+        assert_eq!(bounds.min_longitude, 22.0);
+        assert_eq!(bounds.min_latitude, -5.0);
+        assert!(srtm_bounds("srtm_13_03.hgt").is_none());
+    }
+
+    #[test]
+    fn test_count_voids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("N44W122.hgt");
+        let mut bytes = Vec::new();
+        for value in [100i16, -32768, 2000, -32768, -5] {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(count_voids(&path).unwrap(), (2, 5));
+    }
+
+    fn write_zip(path: &Path, name: &str, content: &[u8]) {
+        let mut writer = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        writer.start_file(name, options).unwrap();
+        io::Write::write_all(&mut writer, content).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("cell.zip");
+        write_zip(&archive, "N44W122.hgt", &vec![1u8; 1201 * 1201 * 2]);
+        let output = extract(dir.path(), &archive, "N44W122.hgt").unwrap();
+        assert_eq!(output, dir.path().join("N44W122.hgt"));
+        assert_eq!(fs::metadata(&output).unwrap().len(), 1201 * 1201 * 2);
+    }
+
+    #[test]
+    fn test_extract_rejects_bad_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("cell.zip");
+        write_zip(&archive, "N44W122.hgt", &vec![1u8; 1000]);
+        assert!(extract(dir.path(), &archive, "N44W122.hgt").is_err());
+        assert!(!dir.path().join("N44W122.hgt").exists());
+    }
+
+    #[test]
+    fn test_extract_rejects_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("cell.zip");
+        write_zip(&archive, "N44W122.hgt", &vec![1u8; 1201 * 1201 * 2]);
+        // corrupt a byte in the middle of the stored data
+        let mut bytes = fs::read(&archive).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&archive, bytes).unwrap();
+        assert!(extract(dir.path(), &archive, "N44W122.hgt").is_err());
+        assert!(!dir.path().join("N44W122.hgt").exists());
+    }
+
+    #[test]
+    fn test_download_to_truncated() {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n0123456789")
+                .unwrap();
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = earthdata::Credentials::Token("token".into());
+        let url = format!("http://{}/N44W122.SRTMGL1.hgt.zip", address);
+        let error = download_to(&url, &dir.path().join("cell.zip"), &credentials).unwrap_err();
+        server.join().unwrap();
+        assert!(matches!(error, DownloadError::Transfer(_)), "{:?}", error);
+        let error: cache::GeneratorError = error.into();
+        assert_eq!(error.kind(), &cache::ErrorKind::Retryable);
+    }
+
     #[test]
     fn test_download() {
         let id = srtm_id(&tile::GeoPoint {
             longitude: -120.0,
             latitude: 50.0,
         });
-        let result = download_tile(PathBuf::new().join("/tmp"), &id).unwrap();
+        let credentials = earthdata::Credentials::from_env().unwrap();
+        let result = download_tile(PathBuf::from("/tmp"), &id, &credentials).unwrap();
         assert_eq!(result.to_string_lossy(), "/tmp/srtm_13_03.hdr");
         assert!(Path::new("/tmp/srtm_13_03.hdr").exists());
     }
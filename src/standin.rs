@@ -0,0 +1,102 @@
+//! Stand ins for upstream HTTP services in tests
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// Canned answer to one request
+pub struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json<T: Into<String>>(body: T) -> Self {
+        Reply {
+            status: 200,
+            content_type: "application/json",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn bytes(content_type: &'static str, body: Vec<u8>) -> Self {
+        Reply {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    /// An error status with a plain text explanation
+    pub fn status(status: u16, body: &str) -> Self {
+        Reply {
+            status,
+            content_type: "text/plain",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// A request as the stand in received it
+pub struct Received {
+    /// header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Received {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Answer each request in turn with the next reply, returning the requests
+/// once every reply is sent
+pub fn serve(replies: Vec<Reply>) -> (String, thread::JoinHandle<Vec<Received>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let mut received = Vec::new();
+        for reply in replies {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = Vec::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    let name = name.trim().to_lowercase();
+                    if name == "content-length" {
+                        length = value.trim().parse().unwrap();
+                    }
+                    headers.push((name, value.trim().to_string()));
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            received.push(Received {
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            });
+            let stream = reader.get_mut();
+            write!(
+                stream,
+                "HTTP/1.1 {} Stand In\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                reply.status,
+                reply.content_type,
+                reply.body.len()
+            )
+            .unwrap();
+            stream.write_all(&reply.body).unwrap();
+        }
+        received
+    });
+    (address, handle)
+}
@@ -5,6 +5,10 @@ use std::process;
 const XRANGE: f64 = 20037508.34;
 const YRANGE: f64 = 20037508.34;
 
+/// Range of zoom levels tiles are served for
+pub const MIN_ZOOM: u8 = 10;
+pub const MAX_ZOOM: u8 = 14;
+
 #[derive(Debug)]
 pub struct Point {
     pub x: f64,
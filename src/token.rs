@@ -35,6 +35,8 @@ impl std::fmt::Display for Token {
 
 pub struct Generator {
     url: String,
+    /// client id and secret, read from the environment when not given
+    credentials: Option<(String, String)>,
     token: tokio::sync::Mutex<Token>,
 }
 
@@ -42,6 +44,7 @@ impl Generator {
     pub fn new<T: Into<String>>(url: T) -> Self {
         Generator {
             url: url.into(),
+            credentials: None,
             token: tokio::sync::Mutex::new(Token {
                 access_token: "".to_string(),
                 expiration: time::Instant::now(),
@@ -49,6 +52,13 @@ impl Generator {
         }
     }
 
+    /// Use this client id and secret instead of `FLYTILE_SENTINEL_ID` and
+    /// `FLYTILE_SENTINEL_SECRET`
+    pub fn with_credentials<T: Into<String>>(mut self, id: T, secret: T) -> Self {
+        self.credentials = Some((id.into(), secret.into()));
+        self
+    }
+
     pub async fn get(&self) -> std::result::Result<String, ProcessingError> {
         // TODO: use message passing for token requests
         let mut token = self.token.lock().await;
@@ -61,14 +71,29 @@ impl Generator {
         Ok(token.access_token.clone())
     }
 
+    /// Get a new token after `stale` was refused, unless another request
+    /// already replaced it
+    pub async fn refresh(&self, stale: &str) -> std::result::Result<String, ProcessingError> {
+        let mut token = self.token.lock().await;
+        if token.access_token == stale {
+            log::info!("regenerate refused token");
+            *token = self.generate().await?;
+        }
+        Ok(token.access_token.clone())
+    }
+
     async fn generate(&self) -> std::result::Result<Token, ProcessingError> {
         log::debug!("requesting token from {}", self.url);
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?;
-        // TODO: cache these?
-        let id = env::var("FLYTILE_SENTINEL_ID")?;
-        let secret = env::var("FLYTILE_SENTINEL_SECRET")?;
+        let (id, secret) = match &self.credentials {
+            Some(credentials) => credentials.clone(),
+            None => (
+                env::var("FLYTILE_SENTINEL_ID")?,
+                env::var("FLYTILE_SENTINEL_SECRET")?,
+            ),
+        };
         let params = [
             ("grant_type", "client_credentials"),
             ("client_id", &id),
@@ -0,0 +1,184 @@
+use image::{ImageFormat, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+/// Processing units used in one month
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MonthlyUsage {
+    /// units estimated from request sizes before they were sent
+    pub estimated: f64,
+    /// units reported by Sentinel Hub, or the estimate when none was reported
+    pub spent: f64,
+    pub requests: u64,
+}
+
+/// Usage as served on the status endpoint
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    /// month like `2025-02`
+    pub month: String,
+    #[serde(flatten)]
+    pub usage: MonthlyUsage,
+    pub budget: Option<f64>,
+    pub remaining: Option<f64>,
+}
+
+/// Raised instead of fetching imagery once the monthly budget is spent
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetExceeded {
+    pub budget: f64,
+}
+
+impl std::error::Error for BudgetExceeded {}
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "monthly processing unit budget of {} is spent",
+            self.budget
+        )
+    }
+}
+
+/// Running monthly tally of the Sentinel Hub processing units spent, shared
+/// by every provider using the same account
+#[derive(Debug, Default)]
+pub struct Usage {
+    /// file the tally is kept in, none to keep it in memory
+    path: Option<PathBuf>,
+    /// units allowed per month
+    budget: Option<f64>,
+    months: Mutex<BTreeMap<String, MonthlyUsage>>,
+}
+
+impl Usage {
+    /// An in memory tally without a budget
+    pub fn unlimited() -> Self {
+        Usage::default()
+    }
+
+    pub fn load(path: PathBuf, budget: Option<f64>) -> Self {
+        let months = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|error| {
+                log::warn!("ignoring invalid usage tally {:?}: {}", path, error);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Usage {
+            path: Some(path),
+            budget,
+            months: Mutex::new(months),
+        }
+    }
+
+    /// Keep the tally in `usage.json` in the cache directory with a monthly
+    /// budget from `FLYTILE_PU_BUDGET`
+    pub fn from_env(cache: &Path) -> Self {
+        let budget = env::var("FLYTILE_PU_BUDGET")
+            .ok()
+            .and_then(|budget| budget.parse::<f64>().ok());
+        Usage::load(cache.join("usage.json"), budget)
+    }
+
+    /// Check more requests may be sent this month
+    pub fn check(&self) -> Result<(), BudgetExceeded> {
+        match self.budget {
+            Some(budget) if self.current().spent >= budget => Err(BudgetExceeded { budget }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add a request to this month's tally
+    pub fn record(&self, estimated: f64, spent: Option<f64>) {
+        let mut months = self.months.lock().unwrap();
+        let month = months.entry(current_month()).or_default();
+        month.estimated += estimated;
+        month.spent += spent.unwrap_or(estimated);
+        month.requests += 1;
+        if let Some(path) = &self.path {
+            let saved = serde_json::to_string_pretty(&*months)
+                .map_err(std::io::Error::from)
+                .and_then(|text| fs::write(path, text));
+            if let Err(error) = saved {
+                log::error!("could not save usage tally {:?}: {}", path, error);
+            }
+        }
+    }
+
+    fn current(&self) -> MonthlyUsage {
+        let months = self.months.lock().unwrap();
+        months.get(&current_month()).cloned().unwrap_or_default()
+    }
+
+    pub fn status(&self) -> Status {
+        let usage = self.current();
+        Status {
+            month: current_month(),
+            remaining: self.budget.map(|budget| (budget - usage.spent).max(0.0)),
+            budget: self.budget,
+            usage,
+        }
+    }
+}
+
+fn current_month() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!("{}-{:02}", now.year(), now.month() as u8)
+}
+
+/// Hatched grey PNG served in place of imagery which would exceed the budget
+pub fn placeholder(size: u32) -> Vec<u8> {
+    let image = RgbaImage::from_fn(size, size, |x, y| {
+        if (x + y) % 16 < 2 {
+            Rgba([96, 96, 96, 160])
+        } else {
+            Rgba([160, 160, 160, 64])
+        }
+    });
+    let mut cursor = Cursor::new(Vec::new());
+    image
+        .write_to(&mut cursor, ImageFormat::Png)
+        .expect("png should encode to memory");
+    cursor.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage.json");
+        let usage = Usage::load(path.clone(), Some(2.0));
+        assert!(usage.check().is_ok());
+        usage.record(1.0, Some(1.5));
+        assert!(usage.check().is_ok());
+        usage.record(0.25, Some(0.5));
+        assert_eq!(usage.check(), Err(BudgetExceeded { budget: 2.0 }));
+    }
+
+    #[test]
+    fn test_tally() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage.json");
+        let usage = Usage::load(path.clone(), Some(10.0));
+        usage.record(1.0, Some(1.5));
+        usage.record(0.25, None);
+        let status = Usage::load(path, Some(10.0)).status();
+        assert_eq!(status.usage.estimated, 1.25);
+        assert_eq!(status.usage.spent, 1.75);
+        assert_eq!(status.usage.requests, 2);
+        assert_eq!(status.remaining, Some(8.25));
+        assert_eq!(status.month.len(), 7);
+        assert!(Usage::unlimited().check().is_ok());
+        assert!(image::load_from_memory(&placeholder(256)).is_ok());
+    }
+}
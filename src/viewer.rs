@@ -1,31 +1,128 @@
-use crate::tile;
 use maud::html;
 use maud::Markup;
+use maud::PreEscaped;
+use time::OffsetDateTime;
 
-pub fn viewer(zoom: u8, x: u32, y: u32) -> Markup {
+const BASE_URL: &str = "https://lagoy.org/tiles";
+
+/// Lists the distinct scene dates of the tiles in a grid, read from the
+/// `.json` metadata of each tile
+///
+/// Runs from the end of the grid, which keeps its elements when htmz moves
+/// them into the page.
+const SCENE_DATES_SCRIPT: &str = r#"(function (viewer) {
+  var list = viewer.querySelector(".scene-dates");
+  var tiles = viewer.querySelectorAll("embed[data-metadata]");
+  Promise.all(Array.from(tiles, function (tile) {
+    return fetch(tile.dataset.metadata)
+      .then(function (response) { return response.ok ? response.json() : {}; })
+      .then(function (metadata) { return metadata.date; }, function () { return null; });
+  })).then(function (dates) {
+    var distinct = Array.from(new Set(dates.filter(Boolean))).sort();
+    list.textContent = distinct.length ? "scenes from " + distinct.join(", ") : "no scenes";
+  });
+})(document.currentScript.parentElement);"#;
+
+/// Imagery date window chosen in the viewer, carried in its links so a view
+/// can be shared
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dates {
+    /// first day like `2025-02-01`
+    pub from: Option<String>,
+    /// last day like `2025-02-14`
+    pub to: Option<String>,
+}
+
+impl Dates {
+    /// Dates as submitted by the date control, where empty means not chosen
+    pub fn new(from: Option<&str>, to: Option<&str>) -> Self {
+        let chosen = |date: Option<&str>| date.filter(|date| !date.is_empty()).map(String::from);
+        Dates {
+            from: chosen(from),
+            to: chosen(to),
+        }
+    }
+
+    /// Query of imagery tiles and their metadata, empty for the latest imagery
+    ///
+    /// A window without an end runs to today and one without a start is the
+    /// latest scene before the day after its end.
+    fn imagery_query(&self) -> String {
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) => format!("?from={}&to={}", from, to),
+            (Some(from), None) => format!("?from={}&to={}", from, OffsetDateTime::now_utc().date()),
+            (None, Some(to)) => match time::Date::parse(
+                to,
+                time::macros::format_description!("[year]-[month]-[day]"),
+            ) {
+                Ok(to) => format!("?before={}", to.next_day().unwrap_or(to)),
+                Err(_) => format!("?before={}", to),
+            },
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Query kept on viewer links, empty for the latest imagery
+    fn query(&self) -> String {
+        let mut query = Vec::new();
+        if let Some(from) = &self.from {
+            query.push(format!("from={}", from));
+        }
+        if let Some(to) = &self.to {
+            query.push(format!("to={}", to));
+        }
+        if query.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", query.join("&"))
+        }
+    }
+}
+
+pub fn viewer(zoom: u8, x: u32, y: u32, dates: &Dates) -> Markup {
     html! {
         html {
-            head {link rel="stylesheet" href="https://lagoy.org/tiles/css/style.css";}
+            head {link rel="stylesheet" href=(format!("{}/css/style.css", BASE_URL));}
             body {
                 iframe hidden name="htmz" onload="setTimeout(()=>document.querySelector(contentWindow.location.hash||null)?.replaceWith(...contentDocument.body.childNodes))" {}
                 h1 {"Tile Viewer"}
-                (image_grid(zoom, x, y, 9, 5))
+                (image_grid(zoom, x, y, 9, 5, dates))
             }
         }
     }
 }
 
-pub fn image_grid(zoom: u8, x: u32, y: u32, nx: u32, ny: u32) -> Markup {
+/// Grid of imagery tiles around a tile with controls to zoom and to choose
+/// the dates shown
+pub fn image_grid(zoom: u8, x: u32, y: u32, nx: u32, ny: u32, dates: &Dates) -> Markup {
     let dy: i32 = (ny / 2) as i32;
     let dx: i32 = (nx / 2) as i32;
+    let query = dates.query();
+    let imagery_query = dates.imagery_query();
     html! {
         div #viewer .viewer {
             div .viewhead {
                 form target="htmz" {
-                    button formaction=(format!("https://lagoy.org/tiles/grid/{}/{}/{}.png#viewer", zoom+1, x*2, y*2)) target="htmz" {"zoom in"}
+                    button formaction=(format!("{}/grid/{}/{}/{}.png{}#viewer", BASE_URL, zoom+1, x*2, y*2, query)) target="htmz" {"zoom in"}
+                    " "
+                    button formaction=(format!("{}/grid/{}/{}/{}.png{}#viewer", BASE_URL, zoom-1, x/2, y/2, query)) target="htmz" {"zoom out"}
+                }
+                // a top level request so the dates and place end up in the address bar
+                form .dates action=(format!("{}/", BASE_URL)) method="get" target="_top" {
+                    input type="hidden" name="zoom" value=(zoom);
+                    input type="hidden" name="x" value=(x);
+                    input type="hidden" name="y" value=(y);
+                    label {"from " input type="date" name="from" value=[dates.from.as_deref()];}
+                    " "
+                    label {"to " input type="date" name="to" value=[dates.to.as_deref()];}
+                    " "
+                    button type="submit" {"show"}
+                    " "
+                    a href=(format!("{}/?zoom={}&x={}&y={}", BASE_URL, zoom, x, y)) target="_top" {"latest"}
                     " "
-                    button formaction=(format!("https://lagoy.org/tiles/grid/{}/{}/{}.png#viewer", zoom-1, x/2, y/2)) target="htmz" {"zoom out"}
+                    a href=(format!("{}/{}", BASE_URL, view_query(zoom, x, y, dates))) target="_top" {"link to this view"}
                 }
+                span .scene-dates {}
             }
             div .viewgrid {
                 @for iy in -dy..dy+1 {
@@ -33,19 +130,62 @@ pub fn image_grid(zoom: u8, x: u32, y: u32, nx: u32, ny: u32) -> Markup {
                     @for ix in -dx..dx+1 {
                         @let xx = x as i32 + ix;
                         div class=(format!("vi{:02}{:02}",iy,ix)) {
-                          a href=(format!("https://lagoy.org/tiles/grid/{}/{}/{}.png#viewer", zoom, xx, yy))
+                          a href=(format!("{}/grid/{}/{}/{}.png{}#viewer", BASE_URL, zoom, xx, yy, query))
                           target="htmz" {
                             embed type="image/png" src=(format!(
-                                "https://lagoy.org/tiles/imagery/latest/{}/{}/{}.png",
-                                zoom, xx, yy));
+                                "{}/imagery/latest/{}/{}/{}.png{}",
+                                BASE_URL, zoom, xx, yy, imagery_query))
+                                data-metadata=(format!(
+                                "{}/imagery/latest/{}/{}/{}.json{}",
+                                BASE_URL, zoom, xx, yy, imagery_query));
                           }
                         }
                     }
                 }
             }
+            script { (PreEscaped(SCENE_DATES_SCRIPT)) }
         }
     }
 }
+
+/// Query of the viewer page showing a place and dates
+fn view_query(zoom: u8, x: u32, y: u32, dates: &Dates) -> String {
+    let dates = dates.query().replacen('?', "&", 1);
+    format!("?zoom={}&x={}&y={}{}", zoom, x, y, dates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        let dates = Dates::new(Some("2025-02-01"), Some("2025-02-14"));
+        assert_eq!(dates.query(), "?from=2025-02-01&to=2025-02-14");
+        assert_eq!(dates.imagery_query(), "?from=2025-02-01&to=2025-02-14");
+        assert_eq!(
+            view_query(12, 669, 1396, &dates),
+            "?zoom=12&x=669&y=1396&from=2025-02-01&to=2025-02-14"
+        );
+        let latest = Dates::new(Some(""), None);
+        assert_eq!(latest, Dates::default());
+        assert_eq!(latest.query(), "");
+        assert_eq!(latest.imagery_query(), "");
+        let before = Dates::new(None, Some("2025-02-28"));
+        assert_eq!(before.imagery_query(), "?before=2025-03-01");
+        let markup = image_grid(12, 669, 1396, 3, 3, &dates).into_string();
+        assert!(
+            markup.contains("/imagery/latest/12/669/1396.png?from=2025-02-01&amp;to=2025-02-14")
+        );
+        assert!(
+            markup.contains("/imagery/latest/12/669/1396.json?from=2025-02-01&amp;to=2025-02-14")
+        );
+        assert!(markup.contains(r#"class="scene-dates""#));
+        assert!(!markup.contains("stamp"));
+        assert!(markup.contains(r#"value="2025-02-14""#));
+    }
+}
+
 // <!DOCTYPE html>
 // <html>
 // <!-- <script src="https://unpkg.com/htmx.org@2.0.4"></script> -->